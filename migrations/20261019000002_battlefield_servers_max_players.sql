@@ -0,0 +1,2 @@
+-- null when the source doesn't report the capacity
+ALTER TABLE battlefield_servers ADD COLUMN IF NOT EXISTS max_players int8;
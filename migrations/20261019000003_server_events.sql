@@ -0,0 +1,11 @@
+-- appeared, disappeared, renamed, wentEmpty and becameFull per server
+CREATE TABLE IF NOT EXISTS server_events (
+    game text,
+    region text,
+    platform text,
+    server_key text,
+    servername text,
+    event text,
+    detail text,
+    timestamp timestamptz
+);
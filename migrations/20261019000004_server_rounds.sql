@@ -0,0 +1,13 @@
+-- finished rounds, for the most played maps and average round length
+CREATE TABLE IF NOT EXISTS server_rounds (
+    game text,
+    region text,
+    platform text,
+    server_key text,
+    servername text,
+    game_map text,
+    game_mode text,
+    round_start timestamptz,
+    round_end timestamptz,
+    duration_seconds int8
+);
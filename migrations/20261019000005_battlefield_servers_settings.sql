@@ -0,0 +1,2 @@
+-- comma separated server settings, null when the source has none
+ALTER TABLE battlefield_servers ADD COLUMN IF NOT EXISTS settings text;
@@ -0,0 +1,11 @@
+-- portal/playground experiences of the detailed gRPC runs
+CREATE TABLE IF NOT EXISTS playground_leaderboard (
+    game text NOT NULL,
+    config_name text NOT NULL,
+    servers int8 NOT NULL,
+    players int8 NOT NULL,
+    previous_players int8,
+    first_seen timestamptz NOT NULL,
+    last_seen timestamptz NOT NULL,
+    PRIMARY KEY (game, config_name)
+);
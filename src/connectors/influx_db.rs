@@ -1,8 +1,13 @@
+use chrono::{DateTime, Utc};
 use futures::stream;
 use influxdb2::models::{data_point::DataPointError, DataPoint};
-use std::collections::HashMap;
+use std::{collections::HashMap, env};
 
-use crate::structs::results;
+use super::spool::{self, SpoolEntry};
+use crate::{
+    error,
+    structs::{battlelog::CrawlReport, results},
+};
 
 pub fn build_data_point(
     frontend_game_name: &str,
@@ -11,12 +16,32 @@ pub fn build_data_point(
     platform: &str,
     field: &str,
     amount: &i64,
+    timestamp: &DateTime<Utc>,
 ) -> Result<DataPoint, DataPointError> {
     DataPoint::builder(frontend_game_name)
         .tag("platform", platform)
         .tag("region", region)
         .tag("type", data_type)
         .field(field, *amount)
+        .timestamp(timestamp.timestamp())
+        .build()
+}
+
+pub fn build_fill_rate_data_point(
+    frontend_game_name: &str,
+    data_type: &str,
+    region: &str,
+    platform: &str,
+    field: &str,
+    fill_rate: f64,
+    timestamp: &DateTime<Utc>,
+) -> Result<DataPoint, DataPointError> {
+    DataPoint::builder(frontend_game_name)
+        .tag("platform", platform)
+        .tag("region", region)
+        .tag("type", data_type)
+        .field(field, fill_rate)
+        .timestamp(timestamp.timestamp())
         .build()
 }
 
@@ -27,6 +52,7 @@ pub fn build_playground_data_point(
     platform: &str,
     playground_name: &str,
     amount: &i64,
+    timestamp: &DateTime<Utc>,
 ) -> Result<DataPoint, DataPointError> {
     DataPoint::builder(frontend_game_name)
         .tag("platform", platform)
@@ -34,16 +60,73 @@ pub fn build_playground_data_point(
         .tag("type", data_type)
         .field("playground", playground_name)
         .field("count", *amount)
+        .timestamp(timestamp.timestamp())
         .build()
 }
 
+// spools the write to disk when influx can't be reached, so it can be replayed later
 pub async fn push_to_database(
     influx_client: &influxdb2::Client,
     frontend_game_name: &str,
     platform: &str,
     platform_result: &HashMap<String, results::RegionResult>,
-) -> anyhow::Result<()> {
-    let bucket = "Game info";
+) -> error::Result<()> {
+    match write_to_database(influx_client, frontend_game_name, platform, platform_result).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            spool::store_failed(SpoolEntry::Influx {
+                frontend_game_name: frontend_game_name.to_string(),
+                platform: platform.to_string(),
+                platform_result: platform_result.to_owned(),
+            })
+            .await;
+            Err(e)
+        }
+    }
+}
+
+// results the anomaly check held back, kept apart so they can be compared or accepted later
+pub async fn push_quarantined(
+    influx_client: &influxdb2::Client,
+    frontend_game_name: &str,
+    platform: &str,
+    platform_result: &HashMap<String, results::RegionResult>,
+) -> error::Result<()> {
+    let quarantine_bucket =
+        env::var("ANOMALY_QUARANTINE_BUCKET").unwrap_or("Game info quarantine".to_string());
+    write_to_bucket(
+        influx_client,
+        &quarantine_bucket,
+        frontend_game_name,
+        platform,
+        platform_result,
+    )
+    .await
+}
+
+pub async fn write_to_database(
+    influx_client: &influxdb2::Client,
+    frontend_game_name: &str,
+    platform: &str,
+    platform_result: &HashMap<String, results::RegionResult>,
+) -> error::Result<()> {
+    write_to_bucket(
+        influx_client,
+        "Game info",
+        frontend_game_name,
+        platform,
+        platform_result,
+    )
+    .await
+}
+
+async fn write_to_bucket(
+    influx_client: &influxdb2::Client,
+    bucket: &str,
+    frontend_game_name: &str,
+    platform: &str,
+    platform_result: &HashMap<String, results::RegionResult>,
+) -> error::Result<()> {
     for (region, region_result) in platform_result {
         let mut points = vec![
             build_data_point(
@@ -53,6 +136,7 @@ pub async fn push_to_database(
                 platform,
                 "serverAmount",
                 &region_result.amounts.server_amount,
+                &region_result.timestamp,
             )?,
             build_data_point(
                 frontend_game_name,
@@ -61,6 +145,7 @@ pub async fn push_to_database(
                 platform,
                 "soldierAmount",
                 &region_result.amounts.soldier_amount,
+                &region_result.timestamp,
             )?,
             build_data_point(
                 frontend_game_name,
@@ -69,6 +154,7 @@ pub async fn push_to_database(
                 platform,
                 "queueAmount",
                 &region_result.amounts.queue_amount,
+                &region_result.timestamp,
             )?,
         ];
         if vec!["bf1", "bfv", "bf4", "battlebit"].contains(&frontend_game_name) {
@@ -80,6 +166,7 @@ pub async fn push_to_database(
                     platform,
                     "spectatorAmount",
                     &region_result.amounts.spectator_amount,
+                    &region_result.timestamp,
                 )?,
                 build_data_point(
                     frontend_game_name,
@@ -88,6 +175,7 @@ pub async fn push_to_database(
                     platform,
                     "diceServerAmount",
                     &region_result.amounts.dice_server_amount,
+                    &region_result.timestamp,
                 )?,
                 build_data_point(
                     frontend_game_name,
@@ -96,6 +184,7 @@ pub async fn push_to_database(
                     platform,
                     "diceSoldierAmount",
                     &region_result.amounts.dice_soldier_amount,
+                    &region_result.timestamp,
                 )?,
                 build_data_point(
                     frontend_game_name,
@@ -104,6 +193,7 @@ pub async fn push_to_database(
                     platform,
                     "diceQueueAmount",
                     &region_result.amounts.dice_queue_amount,
+                    &region_result.timestamp,
                 )?,
                 build_data_point(
                     frontend_game_name,
@@ -112,6 +202,7 @@ pub async fn push_to_database(
                     platform,
                     "diceSpectatorAmount",
                     &region_result.amounts.dice_spectator_amount,
+                    &region_result.timestamp,
                 )?,
                 build_data_point(
                     frontend_game_name,
@@ -120,6 +211,7 @@ pub async fn push_to_database(
                     platform,
                     "communityServerAmount",
                     &region_result.amounts.community_server_amount,
+                    &region_result.timestamp,
                 )?,
                 build_data_point(
                     frontend_game_name,
@@ -128,6 +220,7 @@ pub async fn push_to_database(
                     platform,
                     "communitySoldierAmount",
                     &region_result.amounts.community_soldier_amount,
+                    &region_result.timestamp,
                 )?,
                 build_data_point(
                     frontend_game_name,
@@ -136,6 +229,7 @@ pub async fn push_to_database(
                     platform,
                     "communityQueueAmount",
                     &region_result.amounts.community_queue_amount,
+                    &region_result.timestamp,
                 )?,
                 build_data_point(
                     frontend_game_name,
@@ -144,119 +238,82 @@ pub async fn push_to_database(
                     platform,
                     "communitySpectatorAmount",
                     &region_result.amounts.community_spectator_amount,
+                    &region_result.timestamp,
                 )?,
             ]);
         }
-
-        for (key, value) in &region_result.maps {
-            if !key.is_empty() {
-                points.push(build_data_point(
-                    frontend_game_name,
-                    "maps",
-                    region,
-                    platform,
-                    key,
-                    value,
-                )?);
-            }
-        }
-        for (key, value) in &region_result.map_players {
-            if !key.is_empty() {
-                points.push(build_data_point(
-                    frontend_game_name,
-                    "mapPlayers",
-                    region,
-                    platform,
-                    key,
-                    value,
-                )?);
-            }
-        }
-
-        for (key, value) in &region_result.modes {
-            if !key.is_empty() {
-                points.push(build_data_point(
-                    frontend_game_name,
-                    "modes",
-                    region,
-                    platform,
-                    key,
-                    value,
-                )?);
-            }
-        }
-        for (key, value) in &region_result.mode_players {
-            if !key.is_empty() {
-                points.push(build_data_point(
-                    frontend_game_name,
-                    "modePlayers",
-                    region,
-                    platform,
-                    key,
-                    value,
-                )?);
-            }
+        if let Some(fill_rate) = region_result.amounts.fill_rate() {
+            points.push(build_data_point(
+                frontend_game_name,
+                "amounts",
+                region,
+                platform,
+                "slotAmount",
+                &region_result.amounts.slot_amount,
+                &region_result.timestamp,
+            )?);
+            points.push(build_fill_rate_data_point(
+                frontend_game_name,
+                "amounts",
+                region,
+                platform,
+                "fillRate",
+                fill_rate,
+                &region_result.timestamp,
+            )?);
         }
 
-        for (key, value) in &region_result.owner_platform {
-            if !key.is_empty() {
-                points.push(build_data_point(
-                    frontend_game_name,
-                    "ownerPlatform",
-                    region,
-                    platform,
-                    key,
-                    value,
-                )?);
+        for (dimension, key, tally) in region_result.breakdown.iter() {
+            if key.is_empty() {
+                continue;
             }
-        }
-
-        for (key, value) in &region_result.settings {
-            if !key.is_empty() {
-                points.push(build_data_point(
-                    frontend_game_name,
-                    "settings",
-                    region,
-                    platform,
-                    key,
-                    value,
-                )?);
+            for (data_type, amount) in [
+                (dimension.servers_type(), &tally.servers),
+                (dimension.players_type(), &tally.players),
+            ] {
+                points.push(match dimension {
+                    results::Dimension::Playground => build_playground_data_point(
+                        frontend_game_name,
+                        data_type,
+                        region,
+                        platform,
+                        key,
+                        amount,
+                        &region_result.timestamp,
+                    )?,
+                    _ => build_data_point(
+                        frontend_game_name,
+                        data_type,
+                        region,
+                        platform,
+                        key,
+                        amount,
+                        &region_result.timestamp,
+                    )?,
+                });
             }
-        }
-        for (key, value) in &region_result.settings_players {
-            if !key.is_empty() {
+            // playground points keep the name in a field, capacity is only written for the others
+            if let (Some(fill_rate), false) = (
+                tally.fill_rate(),
+                dimension == results::Dimension::Playground,
+            ) {
                 points.push(build_data_point(
                     frontend_game_name,
-                    "settingPlayers",
+                    &format!("{}Slots", dimension.name()),
                     region,
                     platform,
                     key,
-                    value,
+                    &tally.slots,
+                    &region_result.timestamp,
                 )?);
-            }
-        }
-
-        for (key, value) in &region_result.playground {
-            if !key.is_empty() {
-                points.push(build_playground_data_point(
+                points.push(build_fill_rate_data_point(
                     frontend_game_name,
-                    "playground",
+                    &format!("{}FillRate", dimension.name()),
                     region,
                     platform,
                     key,
-                    value,
-                )?);
-            }
-        }
-        for (key, value) in &region_result.playground_players {
-            if !key.is_empty() {
-                points.push(build_playground_data_point(
-                    frontend_game_name,
-                    "playgroundPlayers",
-                    region,
-                    platform,
-                    key,
-                    value,
+                    fill_rate,
+                    &region_result.timestamp,
                 )?);
             }
         }
@@ -271,10 +328,73 @@ pub async fn push_to_database(
     Ok(())
 }
 
+// the keys of one dimension with the most players, rank 1 is the biggest
+pub async fn push_ranking(
+    influx_client: &influxdb2::Client,
+    frontend_game_name: &str,
+    platform: &str,
+    region_result: &results::RegionResult,
+    dimension: results::Dimension,
+    limit: usize,
+) -> error::Result<()> {
+    let mut tallies: Vec<(&String, &results::Tally)> = region_result
+        .breakdown
+        .iter()
+        .filter(|(key_dimension, key, _)| *key_dimension == dimension && !key.is_empty())
+        .map(|(_, key, tally)| (key, tally))
+        .collect();
+    tallies.sort_by(|(a_key, a), (b_key, b)| {
+        b.players
+            .cmp(&a.players)
+            .then(b.servers.cmp(&a.servers))
+            .then(a_key.cmp(b_key))
+    });
+
+    let mut points = vec![];
+    for (rank, (key, tally)) in tallies.into_iter().take(limit).enumerate() {
+        points.push(
+            DataPoint::builder(frontend_game_name)
+                .tag("platform", platform)
+                .tag("region", &region_result.metadata.region)
+                .tag("type", format!("{}Ranking", dimension.name()))
+                .tag("rank", (rank + 1).to_string())
+                .field(dimension.name(), key.as_str())
+                .field("servers", tally.servers)
+                .field("players", tally.players)
+                .timestamp(region_result.timestamp.timestamp())
+                .build()?,
+        );
+    }
+    influx_client
+        .write_with_precision(
+            "Game info",
+            stream::iter(points),
+            influxdb2::api::write::TimestampPrecision::Seconds,
+        )
+        .await?;
+    Ok(())
+}
+
 pub async fn push_totals(
     influx_client: &influxdb2::Client,
     global_result: &results::RegionResult,
-) -> anyhow::Result<()> {
+) -> error::Result<()> {
+    match write_totals(influx_client, global_result).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            spool::store_failed(SpoolEntry::InfluxTotals {
+                global_result: Box::new(global_result.to_owned()),
+            })
+            .await;
+            Err(e)
+        }
+    }
+}
+
+pub async fn write_totals(
+    influx_client: &influxdb2::Client,
+    global_result: &results::RegionResult,
+) -> error::Result<()> {
     let bucket = "Game info";
     let points = vec![
         build_data_point(
@@ -284,6 +404,7 @@ pub async fn push_totals(
             "global",
             "serverAmount",
             &global_result.amounts.server_amount,
+            &global_result.timestamp,
         )?,
         build_data_point(
             "global",
@@ -292,6 +413,7 @@ pub async fn push_totals(
             "global",
             "soldierAmount",
             &global_result.amounts.soldier_amount,
+            &global_result.timestamp,
         )?,
         build_data_point(
             "global",
@@ -300,6 +422,7 @@ pub async fn push_totals(
             "global",
             "queueAmount",
             &global_result.amounts.queue_amount,
+            &global_result.timestamp,
         )?,
     ];
     influx_client
@@ -312,6 +435,79 @@ pub async fn push_totals(
     Ok(())
 }
 
+pub async fn push_crawl_report(
+    influx_client: &influxdb2::Client,
+    frontend_game_name: &str,
+    report: &CrawlReport,
+) -> error::Result<()> {
+    let bucket = "Game info";
+    let mut points = vec![DataPoint::builder(frontend_game_name)
+        .tag("type", "crawl")
+        .field("pages", report.pages)
+        .field("rows", report.rows)
+        .field("servers", report.servers)
+        .field("duplicates", report.duplicates)
+        .field("exhausted", report.exhausted)
+        .field("completeness", report.completeness())
+        .build()?];
+    // partial means some soldier amounts are from the server list instead of keeper
+    if let Some(verification) = &report.keeper {
+        points.push(
+            DataPoint::builder(frontend_game_name)
+                .tag("type", "keeperVerification")
+                .field("checked", verification.checked)
+                .field("failed", verification.failed)
+                .field("partial", verification.failed > 0)
+                .field(
+                    "averageTeamDifference",
+                    verification.average_team_difference(),
+                )
+                .field(
+                    "averageTicketDifference",
+                    verification.average_ticket_difference(),
+                )
+                .build()?,
+        );
+    }
+    influx_client
+        .write_with_precision(
+            bucket,
+            stream::iter(points),
+            influxdb2::api::write::TimestampPrecision::Seconds,
+        )
+        .await?;
+    Ok(())
+}
+
+// one point per failed source, tagged with the kind of error
+pub async fn push_gather_errors(
+    influx_client: &influxdb2::Client,
+    gather_errors: &[(String, &'static str)],
+) -> error::Result<()> {
+    if gather_errors.is_empty() {
+        return Ok(());
+    }
+    let bucket = "Game info";
+    let mut points = vec![];
+    for (source, kind) in gather_errors {
+        points.push(
+            DataPoint::builder("gatherErrors")
+                .tag("source", source)
+                .tag("kind", *kind)
+                .field("count", 1)
+                .build()?,
+        );
+    }
+    influx_client
+        .write_with_precision(
+            bucket,
+            stream::iter(points),
+            influxdb2::api::write::TimestampPrecision::Seconds,
+        )
+        .await?;
+    Ok(())
+}
+
 // pub fn build_server_data_point(
 //     frontend_game_name: &str,
 //     data_type: &str,
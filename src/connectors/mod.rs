@@ -2,4 +2,6 @@ pub mod clickhouse_db;
 pub mod influx_db;
 pub mod mongo;
 pub mod quest_db;
+pub mod server_registry;
+pub mod spool;
 pub mod timescale_db;
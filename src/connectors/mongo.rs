@@ -1,5 +1,6 @@
-use std::env;
+use std::{collections::HashMap, env};
 
+use super::server_registry::KnownServer;
 use crate::structs::old_games;
 use bf_sparta::cookie::Cookie;
 use bf_sparta::sparta_api;
@@ -12,6 +13,7 @@ use mongodb::{options::ReplaceOptions, results::UpdateResult, Client, Collection
 use serde::{Deserialize, Serialize};
 
 pub struct MongoClient {
+    pub client: Client,
     pub backend_cookies: Collection<BackendCookie>,
     pub community_cookies: Collection<CommunityCookie>,
     pub cookie_check: Collection<CookieCheck>,
@@ -20,6 +22,10 @@ pub struct MongoClient {
     pub player_list: Collection<Document>,
     pub logging: Collection<Document>,
     pub old_games_servers: Collection<old_games::OldGameServerList>,
+    pub game_sessions: Collection<GameSession>,
+    pub cookie_audit: Collection<CookieAudit>,
+    pub anomaly_baselines: Collection<AnomalyBaseline>,
+    pub server_registry: Collection<RegistryScope>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -53,6 +59,19 @@ impl From<CommunityCookie> for Cookie {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CookieAudit {
+    pub _id: String,
+    pub valid: bool,
+    #[serde(
+        rename = "lastChecked",
+        with = "bson::serde_helpers::chrono_datetime_as_bson_datetime"
+    )]
+    pub last_checked: DateTime<Utc>,
+    #[serde(rename = "failureReason")]
+    pub failure_reason: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BackendCookie {
     pub _id: String,
@@ -71,6 +90,57 @@ impl From<BackendCookie> for Cookie {
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameSession {
+    pub _id: String,
+    pub game: String,
+    pub platform: String,
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    #[serde(
+        rename = "createdAt",
+        with = "bson::serde_helpers::chrono_datetime_as_bson_datetime"
+    )]
+    pub created_at: DateTime<Utc>,
+    // set when the session was stored after a successful auth check
+    #[serde(
+        rename = "lastVerified",
+        with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional"
+    )]
+    pub last_verified: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnomalyBaseline {
+    pub _id: String,
+    #[serde(rename = "serverAmounts")]
+    pub server_amounts: Vec<i64>,
+    #[serde(
+        rename = "updatedAt",
+        with = "bson::serde_helpers::chrono_datetime_as_bson_datetime"
+    )]
+    pub updated_at: DateTime<Utc>,
+}
+
+// servers of one game/region/platform, as a list since names can contain dots
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegistryScope {
+    pub _id: String,
+    pub servers: Vec<RegistryServer>,
+    #[serde(
+        rename = "updatedAt",
+        with = "bson::serde_helpers::chrono_datetime_as_bson_datetime"
+    )]
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegistryServer {
+    pub key: String,
+    #[serde(flatten)]
+    pub server: KnownServer,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ManagerInfo {
     #[serde(rename = "communityGroups")]
@@ -108,6 +178,7 @@ impl MongoClient {
         let gamestats_db = client.database("gamestats");
 
         Ok(MongoClient {
+            client: client.clone(),
             cookie_check: db.collection("cookieCheck"),
             community_cookies: db.collection("communityCookies"),
             backend_cookies: db.collection("backendCookies"),
@@ -116,9 +187,17 @@ impl MongoClient {
             player_list: db.collection("playerList"),
             logging: db.collection("logging"),
             old_games_servers: gamestats_db.collection("oldGamesServerList"),
+            game_sessions: db.collection("gameSessions"),
+            cookie_audit: db.collection("cookieAudit"),
+            anomaly_baselines: db.collection("anomalyBaselines"),
+            server_registry: gamestats_db.collection("serverRegistry"),
         })
     }
 
+    pub async fn shutdown(self) {
+        self.client.shutdown().await
+    }
+
     pub async fn gather_managerinfo(&mut self) -> Result<ManagerInfo> {
         let result = ManagerInfo {
             groups_count: self
@@ -243,7 +322,75 @@ impl MongoClient {
         ))
     }
 
+    pub async fn audit_community_cookies(&mut self) -> anyhow::Result<(usize, usize)> {
+        let mut community_cookies = self.community_cookies.find(bson::doc! {}).await?;
+        let mut valid_amount = 0;
+        let mut total_amount = 0;
+        while let Some(maybe_cookie) = community_cookies.next().await {
+            let cookie = maybe_cookie?;
+            let failure_reason =
+                match sparta_api::get_token(cookie.clone().into(), "pc", "tunguska", "en-us").await
+                {
+                    Ok(_) => None,
+                    Err(e) => Some(e.to_string()),
+                };
+            let audit = CookieAudit {
+                _id: cookie._id.clone(),
+                valid: failure_reason.is_none(),
+                last_checked: Utc::now(),
+                failure_reason,
+            };
+            if audit.valid {
+                valid_amount += 1;
+            }
+            total_amount += 1;
+            let options = ReplaceOptions::builder().upsert(true).build();
+            self.cookie_audit
+                .replace_one(bson::doc! {"_id": cookie._id}, audit)
+                .with_options(options)
+                .await?;
+        }
+        Ok((valid_amount, total_amount))
+    }
+
+    // a random valid cookie of a recent audit, so the load is spread over the valid cookies.
+    // audits older than COOKIE_AUDIT_MAX_AGE_MINUTES (default 180) are ignored
+    async fn get_audited_cookie(&mut self) -> anyhow::Result<Option<Cookie>> {
+        let max_age_mins = env::var("COOKIE_AUDIT_MAX_AGE_MINUTES")
+            .ok()
+            .and_then(|mins| mins.parse::<i64>().ok())
+            .unwrap_or(180);
+        let checked_after = Utc::now() - chrono::Duration::minutes(max_age_mins);
+        let mut audits = self
+            .cookie_audit
+            .aggregate(vec![
+                bson::doc! {"$match": {
+                    "valid": true,
+                    "lastChecked": {"$gte": bson::DateTime::from_chrono(checked_after)},
+                }},
+                bson::doc! {"$sample": {"size": 1}},
+            ])
+            .await?;
+        let audit_id = match audits.next().await {
+            Some(audit) => audit?.get_str("_id")?.to_string(),
+            None => return Ok(None),
+        };
+        Ok(self
+            .community_cookies
+            .find_one(bson::doc! {"_id": audit_id})
+            .await?
+            .map(|cookie| cookie.into()))
+    }
+
     pub async fn get_random_cookie(&mut self) -> anyhow::Result<Cookie> {
+        // use a cookie the audit job already validated, only walk the cookies when there is none
+        match self.get_audited_cookie().await {
+            Ok(Some(cookie)) => return Ok(cookie),
+            Ok(None) => {
+                log::info!("No recently audited cookie available, checking cookies one by one")
+            }
+            Err(e) => log::warn!("Failed to get audited cookie, {}", e),
+        };
         let mut cookie_check = self
             .cookie_check
             .find(bson::doc! {})
@@ -301,4 +448,125 @@ impl MongoClient {
             .find_one(bson::doc! {"_id": game_name})
             .await
     }
+
+    pub async fn get_sessions(
+        &mut self,
+    ) -> anyhow::Result<HashMap<String, HashMap<String, String>>> {
+        let mut stored_sessions = self.game_sessions.find(bson::doc! {}).await?;
+        let mut sessions: HashMap<String, HashMap<String, String>> = HashMap::new();
+        while let Some(maybe_session) = stored_sessions.next().await {
+            let session = maybe_session?;
+            sessions
+                .entry(session.game)
+                .or_default()
+                .insert(session.platform, session.session_id);
+        }
+        Ok(sessions)
+    }
+
+    // only writes the platforms where the session id changed since the previous one
+    pub async fn push_sessions(
+        &mut self,
+        game: &str,
+        previous_sessions: &HashMap<String, String>,
+        sessions: &HashMap<String, String>,
+        verified: bool,
+    ) -> anyhow::Result<()> {
+        let now = Utc::now();
+        for (platform, session_id) in sessions {
+            if session_id.is_empty() || previous_sessions.get(platform) == Some(session_id) {
+                continue;
+            }
+            let id = format!("{}-{}", game, platform);
+            let session = GameSession {
+                _id: id.clone(),
+                game: game.to_string(),
+                platform: platform.to_string(),
+                session_id: session_id.to_string(),
+                created_at: now,
+                last_verified: verified.then_some(now),
+            };
+            let options = ReplaceOptions::builder().upsert(true).build();
+            self.game_sessions
+                .replace_one(bson::doc! {"_id": id}, session)
+                .with_options(options)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn get_anomaly_baselines(&mut self) -> anyhow::Result<HashMap<String, Vec<i64>>> {
+        let mut stored_baselines = self.anomaly_baselines.find(bson::doc! {}).await?;
+        let mut baselines = HashMap::new();
+        while let Some(maybe_baseline) = stored_baselines.next().await {
+            let baseline = maybe_baseline?;
+            baselines.insert(baseline._id, baseline.server_amounts);
+        }
+        Ok(baselines)
+    }
+
+    pub async fn push_anomaly_baselines(
+        &mut self,
+        baselines: &HashMap<String, Vec<i64>>,
+    ) -> anyhow::Result<()> {
+        let now = Utc::now();
+        for (key, server_amounts) in baselines {
+            let baseline = AnomalyBaseline {
+                _id: key.to_string(),
+                server_amounts: server_amounts.to_owned(),
+                updated_at: now,
+            };
+            let options = ReplaceOptions::builder().upsert(true).build();
+            self.anomaly_baselines
+                .replace_one(bson::doc! {"_id": key}, baseline)
+                .with_options(options)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn get_server_registry(
+        &mut self,
+    ) -> anyhow::Result<HashMap<String, HashMap<String, KnownServer>>> {
+        let mut stored_scopes = self.server_registry.find(bson::doc! {}).await?;
+        let mut registry = HashMap::new();
+        while let Some(maybe_scope) = stored_scopes.next().await {
+            let scope = maybe_scope?;
+            registry.insert(
+                scope._id,
+                scope
+                    .servers
+                    .into_iter()
+                    .map(|registered| (registered.key, registered.server))
+                    .collect(),
+            );
+        }
+        Ok(registry)
+    }
+
+    pub async fn push_server_registry(
+        &mut self,
+        registry: &HashMap<String, HashMap<String, KnownServer>>,
+    ) -> anyhow::Result<()> {
+        let now = Utc::now();
+        for (scope, known_servers) in registry {
+            let stored_scope = RegistryScope {
+                _id: scope.to_string(),
+                servers: known_servers
+                    .iter()
+                    .map(|(key, server)| RegistryServer {
+                        key: key.to_string(),
+                        server: server.clone(),
+                    })
+                    .collect(),
+                updated_at: now,
+            };
+            let options = ReplaceOptions::builder().upsert(true).build();
+            self.server_registry
+                .replace_one(bson::doc! {"_id": scope}, stored_scope)
+                .with_options(options)
+                .await?;
+        }
+        Ok(())
+    }
 }
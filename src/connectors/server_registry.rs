@@ -0,0 +1,416 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    sync::LazyLock,
+};
+use tokio::sync::Mutex;
+
+use super::mongo::MongoClient;
+use crate::structs::server_info::ServerInfo;
+
+// last seen state of every known server, per game/region/platform. stored in mongo,
+// so a restart doesn't report every server as appeared again
+static REGISTRY: LazyLock<Mutex<HashMap<String, HashMap<String, KnownServer>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KnownServer {
+    pub name: String,
+    pub soldiers: i64,
+    #[serde(rename = "maxPlayers")]
+    pub max_players: Option<i64>,
+    #[serde(
+        rename = "firstSeen",
+        with = "bson::serde_helpers::chrono_datetime_as_bson_datetime"
+    )]
+    pub first_seen: DateTime<Utc>,
+    #[serde(
+        rename = "lastSeen",
+        with = "bson::serde_helpers::chrono_datetime_as_bson_datetime"
+    )]
+    pub last_seen: DateTime<Utc>,
+    // cycles in a row the server wasn't in the list
+    pub missed: u32,
+    #[serde(default)]
+    pub map: String,
+    #[serde(default)]
+    pub mode: String,
+    // when the current map was first seen, None until a map change was seen
+    #[serde(
+        rename = "roundStart",
+        default,
+        with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional"
+    )]
+    pub round_start: Option<DateTime<Utc>>,
+    #[serde(rename = "gameId", default)]
+    pub game_id: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Appeared,
+    Disappeared,
+    Renamed,
+    WentEmpty,
+    BecameFull,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Appeared => "appeared",
+            EventKind::Disappeared => "disappeared",
+            EventKind::Renamed => "renamed",
+            EventKind::WentEmpty => "wentEmpty",
+            EventKind::BecameFull => "becameFull",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerEvent {
+    pub server_key: String,
+    pub name: String,
+    pub kind: EventKind,
+    // previous name for renames
+    pub detail: Option<String>,
+}
+
+// a finished round, start and end are the cycles the map change was seen in
+#[derive(Debug, Clone)]
+pub struct Round {
+    pub server_key: String,
+    pub name: String,
+    pub map: String,
+    pub mode: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl Round {
+    pub fn duration_seconds(&self) -> i64 {
+        (self.end - self.start).num_seconds()
+    }
+}
+
+// guid when the source has one, otherwise the game id, battlebit only has the name
+pub fn server_key(server_info: &ServerInfo) -> String {
+    if !server_info.guid.is_empty() {
+        server_info.guid.clone()
+    } else if !server_info.game_id.is_empty() {
+        format!("gameId:{}", server_info.game_id)
+    } else {
+        format!("name:{}", server_info.name)
+    }
+}
+
+// a server only counts as gone after missing this many cycles, partial lists happen
+fn missing_cycles() -> u32 {
+    env::var("REGISTRY_MISSING_CYCLES")
+        .ok()
+        .and_then(|cycles| cycles.parse::<u32>().ok())
+        .unwrap_or(2)
+        .max(1)
+}
+
+pub async fn load(mongo_client: &mut MongoClient) -> anyhow::Result<usize> {
+    let stored = mongo_client.get_server_registry().await?;
+    let known = stored.values().map(|servers| servers.len()).sum();
+    *REGISTRY.lock().await = stored;
+    Ok(known)
+}
+
+pub async fn save(mongo_client: &mut MongoClient) -> anyhow::Result<()> {
+    let registry = REGISTRY.lock().await.clone();
+    mongo_client.push_server_registry(&registry).await
+}
+
+// compares the servers of one gather to the previous ones of the same scope,
+// returns the lifecycle events and the rounds that ended since
+pub async fn observe(
+    frontend_game_name: &str,
+    region: &str,
+    platform: &str,
+    server_infos: &[ServerInfo],
+    timestamp: &DateTime<Utc>,
+) -> (Vec<ServerEvent>, Vec<Round>) {
+    // an empty list is more likely a failed gather than every server going down
+    if server_infos.is_empty() {
+        return (vec![], vec![]);
+    }
+    let mut registry = REGISTRY.lock().await;
+    let scope = format!("{}/{}/{}", frontend_game_name, region, platform);
+    // the first list of a scope is the starting point, not a wave of new servers
+    let seeding = !registry.contains_key(&scope);
+    let known_servers = registry.entry(scope).or_default();
+    let mut events = vec![];
+    let mut rounds = vec![];
+    let mut seen = HashSet::new();
+
+    for server_info in server_infos {
+        if server_info.name.is_empty() {
+            continue;
+        }
+        let key = server_key(server_info);
+        seen.insert(key.clone());
+        let event = |kind: EventKind, detail: Option<String>| ServerEvent {
+            server_key: key.clone(),
+            name: server_info.name.clone(),
+            kind,
+            detail,
+        };
+
+        match known_servers.get_mut(&key) {
+            Some(known) => {
+                if known.name != server_info.name {
+                    events.push(event(EventKind::Renamed, Some(known.name.clone())));
+                }
+                if known.soldiers > 0 && server_info.soldiers == 0 {
+                    events.push(event(EventKind::WentEmpty, None));
+                }
+                if let Some(max_players) = server_info.max_players {
+                    if known.soldiers < max_players && server_info.soldiers >= max_players {
+                        events.push(event(EventKind::BecameFull, None));
+                    }
+                }
+                // sources without map info can't have rounds
+                if !server_info.map.is_empty()
+                    && (known.map != server_info.map || known.mode != server_info.mode)
+                {
+                    // the round running when a server was first seen has no known start
+                    if let (Some(start), false) = (known.round_start, known.map.is_empty()) {
+                        rounds.push(Round {
+                            server_key: key.clone(),
+                            name: known.name.clone(),
+                            map: known.map.clone(),
+                            mode: known.mode.clone(),
+                            start,
+                            end: *timestamp,
+                        });
+                    }
+                    known.round_start = match known.map.is_empty() {
+                        true => None,
+                        false => Some(*timestamp),
+                    };
+                }
+                known.name = server_info.name.clone();
+                // a cycle without map info keeps the running round
+                if !server_info.map.is_empty() {
+                    known.map = server_info.map.clone();
+                    known.mode = server_info.mode.clone();
+                }
+                known.soldiers = server_info.soldiers;
+                known.game_id = server_info.game_id.clone();
+                known.max_players = server_info.max_players;
+                known.last_seen = *timestamp;
+                known.missed = 0;
+            }
+            None => {
+                if !seeding {
+                    events.push(event(EventKind::Appeared, None));
+                }
+                known_servers.insert(
+                    key.clone(),
+                    KnownServer {
+                        name: server_info.name.clone(),
+                        soldiers: server_info.soldiers,
+                        max_players: server_info.max_players,
+                        first_seen: *timestamp,
+                        last_seen: *timestamp,
+                        missed: 0,
+                        map: server_info.map.clone(),
+                        mode: server_info.mode.clone(),
+                        round_start: None,
+                        game_id: server_info.game_id.clone(),
+                    },
+                );
+            }
+        }
+    }
+
+    let missing_cycles = missing_cycles();
+    known_servers.retain(|key, known| {
+        if seen.contains(key) {
+            return true;
+        }
+        known.missed += 1;
+        if known.missed < missing_cycles {
+            return true;
+        }
+        events.push(ServerEvent {
+            server_key: key.clone(),
+            name: known.name.clone(),
+            kind: EventKind::Disappeared,
+            detail: None,
+        });
+        false
+    });
+
+    (events, rounds)
+}
+
+// populated servers of the last gather with their region, for follow-up requests per server
+pub async fn populated(frontend_game_name: &str) -> Vec<(String, KnownServer)> {
+    let registry = REGISTRY.lock().await;
+    let mut servers = vec![];
+    for (scope, known_servers) in registry.iter() {
+        let mut parts = scope.split('/');
+        if parts.next() != Some(frontend_game_name) {
+            continue;
+        }
+        let region = parts.next().unwrap_or_default();
+        for known in known_servers.values() {
+            if known.missed == 0 && known.soldiers > 0 && !known.game_id.is_empty() {
+                servers.push((region.to_string(), known.clone()));
+            }
+        }
+    }
+    servers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(guid: &str, name: &str, soldiers: i64, map: &str) -> ServerInfo {
+        ServerInfo {
+            name: name.to_string(),
+            guid: guid.to_string(),
+            game_id: String::new(),
+            soldiers,
+            queue: 0,
+            mode: "conquest".to_string(),
+            map: map.to_string(),
+            is_official: None,
+            max_players: Some(64),
+            settings: vec![],
+        }
+    }
+
+    fn minutes(minutes: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap() + chrono::Duration::minutes(minutes)
+    }
+
+    fn kinds(events: &[ServerEvent]) -> Vec<(&str, EventKind)> {
+        events
+            .iter()
+            .map(|event| (event.server_key.as_str(), event.kind))
+            .collect()
+    }
+
+    #[test]
+    fn server_key_falls_back_to_game_id_and_name() {
+        let mut server_info = server("guid", "name", 0, "");
+        server_info.game_id = "123".to_string();
+        assert_eq!(server_key(&server_info), "guid");
+        server_info.guid.clear();
+        assert_eq!(server_key(&server_info), "gameId:123");
+        server_info.game_id.clear();
+        assert_eq!(server_key(&server_info), "name:name");
+    }
+
+    // every test has its own game, the registry is shared
+    #[tokio::test]
+    async fn lifecycle_events() {
+        let observe = |servers: Vec<ServerInfo>, minute: i64| async move {
+            observe("events-test", "EU", "pc", &servers, &minutes(minute))
+                .await
+                .0
+        };
+        // the first list only seeds the scope
+        let events = observe(
+            vec![server("a", "alpha", 10, ""), server("b", "bravo", 63, "")],
+            0,
+        )
+        .await;
+        assert!(events.is_empty());
+
+        let events = observe(
+            vec![
+                server("a", "alpha renamed", 0, ""),
+                server("b", "bravo", 64, ""),
+                server("c", "charlie", 1, ""),
+            ],
+            5,
+        )
+        .await;
+        assert_eq!(
+            kinds(&events),
+            vec![
+                ("a", EventKind::Renamed),
+                ("a", EventKind::WentEmpty),
+                ("b", EventKind::BecameFull),
+                ("c", EventKind::Appeared),
+            ]
+        );
+        assert_eq!(events[0].detail.as_deref(), Some("alpha"));
+        assert_eq!(events[0].name, "alpha renamed");
+
+        // gone after missing two cycles in a row
+        let remaining = vec![
+            server("a", "alpha renamed", 0, ""),
+            server("b", "bravo", 64, ""),
+        ];
+        assert!(observe(remaining.clone(), 10).await.is_empty());
+        assert_eq!(
+            kinds(&observe(remaining.clone(), 15).await),
+            vec![("c", EventKind::Disappeared)]
+        );
+        // an empty list is ignored instead of dropping every server
+        assert!(observe(vec![], 20).await.is_empty());
+        assert!(observe(remaining, 25).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rounds_end_on_map_changes() {
+        let observe = |map: &'static str, minute: i64| async move {
+            observe(
+                "rounds-test",
+                "EU",
+                "pc",
+                &[server("a", "alpha", 10, map)],
+                &minutes(minute),
+            )
+            .await
+            .1
+        };
+        assert!(observe("wake", 0).await.is_empty());
+        // the first round has no known start
+        assert!(observe("kubra", 5).await.is_empty());
+        assert!(observe("kubra", 10).await.is_empty());
+        // sources without map info don't end rounds
+        assert!(observe("", 15).await.is_empty());
+        assert!(observe("kubra", 20).await.is_empty());
+
+        let rounds = observe("karkand", 35).await;
+        assert_eq!(rounds.len(), 1);
+        assert_eq!(rounds[0].map, "kubra");
+        assert_eq!(rounds[0].mode, "conquest");
+        assert_eq!(rounds[0].start, minutes(5));
+        assert_eq!(rounds[0].end, minutes(35));
+        assert_eq!(rounds[0].duration_seconds(), 30 * 60);
+    }
+
+    #[tokio::test]
+    async fn populated_servers_need_a_game_id_and_players() {
+        let mut with_game_id = server("a", "alpha", 10, "");
+        with_game_id.game_id = "1".to_string();
+        let mut empty = server("b", "bravo", 0, "");
+        empty.game_id = "2".to_string();
+        let without_game_id = server("c", "charlie", 10, "");
+        observe(
+            "populated-test",
+            "EU",
+            "pc",
+            &[with_game_id, empty, without_game_id],
+            &minutes(0),
+        )
+        .await;
+        let populated = populated("populated-test").await;
+        assert_eq!(populated.len(), 1);
+        assert_eq!(populated[0].0, "EU");
+        assert_eq!(populated[0].1.game_id, "1");
+    }
+}
@@ -0,0 +1,468 @@
+use futures::stream;
+use influxdb2::models::DataPoint;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use std::{
+    collections::HashMap, env, future::Future, io::ErrorKind, path::PathBuf, sync::OnceLock,
+};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+use super::{influx_db, timescale_db};
+use crate::{
+    error,
+    structs::{results, server_info},
+};
+
+const SINKS: [&str; 2] = ["influx", "timescale"];
+
+// appends and replays can't interleave, otherwise a replay could drop new entries
+static SPOOL_LOCK: Mutex<()> = Mutex::const_new(());
+static SPOOL_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SpoolEntry {
+    Influx {
+        frontend_game_name: String,
+        platform: String,
+        platform_result: HashMap<String, results::RegionResult>,
+    },
+    InfluxTotals {
+        global_result: Box<results::RegionResult>,
+    },
+    Timescale {
+        frontend_game_name: String,
+        region: String,
+        platform: String,
+        server_infos: Vec<server_info::ServerInfo>,
+    },
+}
+
+// a spool line, attempts counts the replays the sink rejected
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SpooledWrite {
+    #[serde(flatten)]
+    entry: SpoolEntry,
+    #[serde(default)]
+    attempts: u32,
+}
+
+impl SpoolEntry {
+    fn sink(&self) -> &'static str {
+        match self {
+            SpoolEntry::Influx { .. } | SpoolEntry::InfluxTotals { .. } => "influx",
+            SpoolEntry::Timescale { .. } => "timescale",
+        }
+    }
+
+    async fn write(&self, pool: &PgPool, influx_client: &influxdb2::Client) -> error::Result<()> {
+        match self {
+            SpoolEntry::Influx {
+                frontend_game_name,
+                platform,
+                platform_result,
+            } => {
+                influx_db::write_to_database(
+                    influx_client,
+                    frontend_game_name,
+                    platform,
+                    platform_result,
+                )
+                .await
+            }
+            SpoolEntry::InfluxTotals { global_result } => {
+                influx_db::write_totals(influx_client, global_result).await
+            }
+            SpoolEntry::Timescale {
+                frontend_game_name,
+                region,
+                platform,
+                server_infos,
+            } => {
+                timescale_db::insert_servers(
+                    pool,
+                    frontend_game_name,
+                    region,
+                    platform,
+                    server_infos,
+                )
+                .await
+            }
+        }
+    }
+}
+
+// the spool has to survive a redeploy, so SPOOL_DIR should be a mounted volume.
+// without it failed writes are dropped like before
+pub fn init(dir: &str) {
+    if SPOOL_DIR.set(PathBuf::from(dir)).is_err() {
+        log::warn!("Spool directory was already set");
+    }
+}
+
+pub fn is_enabled() -> bool {
+    SPOOL_DIR.get().is_some()
+}
+
+fn spool_path(sink: &str) -> anyhow::Result<PathBuf> {
+    match SPOOL_DIR.get() {
+        Some(dir) => Ok(dir.join(format!("{}.jsonl", sink))),
+        None => anyhow::bail!("spool directory isn't set"),
+    }
+}
+
+// writes the sink keeps rejecting end up here, they aren't replayed again
+fn dead_letter_path(sink: &str) -> anyhow::Result<PathBuf> {
+    Ok(spool_path(sink)?.with_extension("dead.jsonl"))
+}
+
+fn max_bytes() -> u64 {
+    env::var("SPOOL_MAX_MB")
+        .ok()
+        .and_then(|mb| mb.parse::<u64>().ok())
+        .unwrap_or(256)
+        * 1024
+        * 1024
+}
+
+fn max_attempts() -> u32 {
+    env::var("SPOOL_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|attempts| attempts.parse::<u32>().ok())
+        .unwrap_or(3)
+        .max(1)
+}
+
+async fn append(path: &PathBuf, lines: &[String]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    for line in lines {
+        file.write_all(format!("{}\n", line).as_bytes()).await?;
+    }
+    file.sync_data().await?;
+    Ok(())
+}
+
+async fn store(entry: &SpoolEntry) -> anyhow::Result<()> {
+    let _lock = SPOOL_LOCK.lock().await;
+    let path = spool_path(entry.sink())?;
+    let line = serde_json::to_string(&SpooledWrite {
+        entry: entry.clone(),
+        attempts: 0,
+    })?;
+
+    let current_size = match fs::metadata(&path).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    if current_size + line.len() as u64 + 1 > max_bytes() {
+        anyhow::bail!("{} spool is full ({} bytes)", entry.sink(), current_size);
+    }
+
+    append(&path, &[line]).await
+}
+
+// used by the sinks when a write fails, the original error is still returned by them
+pub async fn store_failed(entry: SpoolEntry) {
+    if !is_enabled() {
+        return;
+    }
+    match store(&entry).await {
+        Ok(_) => log::warn!("Spooled failed {} write to disk", entry.sink()),
+        Err(e) => log::error!(
+            "Failed to spool {} write, dropping it: {:#?}",
+            entry.sink(),
+            e
+        ),
+    }
+}
+
+// write sends an entry to its sink
+async fn replay_sink<F, Fut>(sink: &str, mut write: F) -> anyhow::Result<(usize, usize)>
+where
+    F: FnMut(SpoolEntry) -> Fut,
+    Fut: Future<Output = error::Result<()>>,
+{
+    let path = spool_path(sink)?;
+    let content = match fs::read_to_string(&path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((0, 0)),
+        Err(e) => return Err(e.into()),
+    };
+    let lines: Vec<&str> = content.lines().filter(|line| !line.is_empty()).collect();
+
+    // oldest first. an unavailable sink stops the replay so the order is kept, a rejected
+    // write is retried on the next replay and moved to the dead letters after SPOOL_MAX_ATTEMPTS
+    let mut replayed = 0;
+    let mut kept: Vec<String> = vec![];
+    let mut dead_letters: Vec<String> = vec![];
+    for (index, line) in lines.iter().enumerate() {
+        let mut spooled = match serde_json::from_str::<SpooledWrite>(line) {
+            Ok(spooled) => spooled,
+            Err(e) => {
+                log::error!(
+                    "Moving unreadable {} spool entry to dead letters: {:#?}",
+                    sink,
+                    e
+                );
+                dead_letters.push(line.to_string());
+                continue;
+            }
+        };
+        match write(spooled.entry.clone()).await {
+            Ok(_) => replayed += 1,
+            Err(e) if e.is_sink_unavailable() => {
+                log::warn!(
+                    "{} still unavailable, keeping {} spooled writes: {}",
+                    sink,
+                    lines.len() - index,
+                    e
+                );
+                kept.extend(lines[index..].iter().map(|line| line.to_string()));
+                break;
+            }
+            Err(e) => {
+                spooled.attempts += 1;
+                if spooled.attempts >= max_attempts() {
+                    log::error!(
+                        "Moving {} spool entry to dead letters after {} attempts: {}",
+                        sink,
+                        spooled.attempts,
+                        e
+                    );
+                    dead_letters.push(serde_json::to_string(&spooled)?);
+                } else {
+                    log::warn!("{} rejected a spooled write, keeping it: {}", sink, e);
+                    kept.push(serde_json::to_string(&spooled)?);
+                }
+            }
+        }
+    }
+
+    if !dead_letters.is_empty() {
+        append(&dead_letter_path(sink)?, &dead_letters).await?;
+    }
+    if kept.is_empty() {
+        fs::remove_file(&path).await?;
+    } else {
+        let tmp_path = path.with_extension("jsonl.tmp");
+        fs::write(&tmp_path, kept.join("\n") + "\n").await?;
+        fs::rename(&tmp_path, &path).await?;
+    }
+    Ok((replayed, dead_letters.len()))
+}
+
+pub async fn replay(pool: &PgPool, influx_client: &influxdb2::Client) {
+    if !is_enabled() {
+        return;
+    }
+    let _lock = SPOOL_LOCK.lock().await;
+    for sink in SINKS {
+        let write = |entry: SpoolEntry| async move { entry.write(pool, influx_client).await };
+        match replay_sink(sink, write).await {
+            Ok((0, 0)) => {}
+            Ok((replayed, dead_letters)) => log::info!(
+                "Replayed {} spooled {} writes, {} moved to dead letters",
+                replayed,
+                sink,
+                dead_letters
+            ),
+            Err(e) => log::error!("Failed to replay {} spool: {:#?}", sink, e),
+        }
+    }
+}
+
+async fn count_lines(path: anyhow::Result<PathBuf>) -> (i64, i64) {
+    let path = match path {
+        Ok(path) => path,
+        Err(_) => return (0, 0),
+    };
+    match fs::read_to_string(path).await {
+        Ok(content) => (
+            content.lines().filter(|line| !line.is_empty()).count() as i64,
+            content.len() as i64,
+        ),
+        Err(_) => (0, 0),
+    }
+}
+
+// amount of entries and bytes still waiting, and dead letters, per sink
+pub async fn depth() -> HashMap<&'static str, (i64, i64, i64)> {
+    let _lock = SPOOL_LOCK.lock().await;
+    let mut depth = HashMap::new();
+    for sink in SINKS {
+        let (entries, bytes) = count_lines(spool_path(sink)).await;
+        let (dead_letters, _) = count_lines(dead_letter_path(sink)).await;
+        depth.insert(sink, (entries, bytes, dead_letters));
+    }
+    depth
+}
+
+pub async fn push_depth(influx_client: &influxdb2::Client) -> anyhow::Result<()> {
+    if !is_enabled() {
+        return Ok(());
+    }
+    let mut points = vec![];
+    for (sink, (entries, bytes, dead_letters)) in depth().await {
+        points.push(
+            DataPoint::builder("spool")
+                .tag("sink", sink)
+                .field("entries", entries)
+                .field("bytes", bytes)
+                .field("deadLetters", dead_letters)
+                .build()?,
+        );
+    }
+    influx_client
+        .write_with_precision(
+            "Game info",
+            stream::iter(points),
+            influxdb2::api::write::TimestampPrecision::Seconds,
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::GatherError;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex as StdMutex,
+    };
+
+    // the spool directory is set once per process, every test uses its own sink
+    fn init_test_dir() {
+        let dir = env::temp_dir().join(format!("spool-test-{}", std::process::id()));
+        if !is_enabled() {
+            init(dir.to_str().unwrap());
+        }
+    }
+
+    fn timescale_entry(region: &str) -> SpoolEntry {
+        SpoolEntry::Timescale {
+            frontend_game_name: "bf4".to_string(),
+            region: region.to_string(),
+            platform: "pc".to_string(),
+            server_infos: vec![],
+        }
+    }
+
+    fn unavailable() -> GatherError {
+        GatherError::Sink {
+            sink: "timescale",
+            message: "connection refused".to_string(),
+            unavailable: true,
+        }
+    }
+
+    fn rejected() -> GatherError {
+        GatherError::Sink {
+            sink: "timescale",
+            message: "invalid value".to_string(),
+            unavailable: false,
+        }
+    }
+
+    fn region(entry: &SpoolEntry) -> String {
+        match entry {
+            SpoolEntry::Timescale { region, .. } => region.clone(),
+            _ => panic!("not a timescale entry"),
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_in_order_and_keeps_writes_while_unavailable() {
+        init_test_dir();
+        for region in ["EU", "NAm", "Asia"] {
+            store_failed(timescale_entry(region)).await;
+        }
+        assert_eq!(depth().await["timescale"].0, 3);
+
+        // down after the first write, the rest waits for the next replay
+        let written = StdMutex::new(vec![]);
+        let result = replay_sink("timescale", |entry| {
+            let mut written = written.lock().unwrap();
+            let available = written.is_empty();
+            written.push(region(&entry));
+            async move {
+                match available {
+                    true => Ok(()),
+                    false => Err(unavailable()),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(result, (1, 0));
+        assert_eq!(*written.lock().unwrap(), vec!["EU", "NAm"]);
+        assert_eq!(depth().await["timescale"].0, 2);
+
+        let written = StdMutex::new(vec![]);
+        let result = replay_sink("timescale", |entry| {
+            written.lock().unwrap().push(region(&entry));
+            async { Ok(()) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(result, (2, 0));
+        assert_eq!(*written.lock().unwrap(), vec!["NAm", "Asia"]);
+        assert!(!spool_path("timescale").unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn rejected_writes_become_dead_letters() {
+        init_test_dir();
+        let entry = SpooledWrite {
+            entry: timescale_entry("EU"),
+            attempts: 0,
+        };
+        let line = serde_json::to_string(&entry).unwrap();
+        append(
+            &spool_path("rejecting").unwrap(),
+            &[line, "{broken".to_string()],
+        )
+        .await
+        .unwrap();
+
+        let writes = AtomicUsize::new(0);
+        let reject = || async {
+            writes.fetch_add(1, Ordering::SeqCst);
+            Err(rejected())
+        };
+        // the unreadable line goes right away, the rejected one after 3 attempts
+        assert_eq!(
+            replay_sink("rejecting", |_| reject()).await.unwrap(),
+            (0, 1)
+        );
+        assert_eq!(
+            replay_sink("rejecting", |_| reject()).await.unwrap(),
+            (0, 0)
+        );
+        assert_eq!(
+            replay_sink("rejecting", |_| reject()).await.unwrap(),
+            (0, 1)
+        );
+        assert_eq!(writes.load(Ordering::SeqCst), 3);
+        assert!(!spool_path("rejecting").unwrap().exists());
+
+        let dead_letters = fs::read_to_string(dead_letter_path("rejecting").unwrap())
+            .await
+            .unwrap();
+        let dead_letters: Vec<&str> = dead_letters.lines().collect();
+        assert_eq!(dead_letters[0], "{broken");
+        let spooled: SpooledWrite = serde_json::from_str(dead_letters[1]).unwrap();
+        assert_eq!(spooled.attempts, 3);
+        assert_eq!(region(&spooled.entry), "EU");
+        fs::remove_file(dead_letter_path("rejecting").unwrap())
+            .await
+            .unwrap();
+    }
+}
@@ -1,14 +1,85 @@
+use chrono::{DateTime, Utc};
 use sqlx::postgres::PgPool;
 
-use crate::structs::server_info;
+use super::{
+    server_registry::{self, Round, ServerEvent},
+    spool::{self, SpoolEntry},
+};
+use crate::{
+    error,
+    structs::{
+        playground::PlaygroundEntry,
+        results::{Dimension, RegionResult},
+        server_info,
+    },
+};
 
+// spools the write to disk when postgres can't be reached, so it can be replayed later.
+// replayed rows get the time of the replay
 pub async fn push_server(
     pool: &PgPool,
     frontend_game_name: &str,
     region: &str,
     platform: &str,
     server_infos: Vec<server_info::ServerInfo>,
-) -> anyhow::Result<()> {
+) -> error::Result<()> {
+    let timestamp = Utc::now();
+    let (events, rounds) = server_registry::observe(
+        frontend_game_name,
+        region,
+        platform,
+        &server_infos,
+        &timestamp,
+    )
+    .await;
+    if let Err(e) = insert_server_events(
+        pool,
+        frontend_game_name,
+        region,
+        platform,
+        &timestamp,
+        &events,
+    )
+    .await
+    {
+        log::error!(
+            "{} region {} failed to push server events: {:#?}",
+            frontend_game_name,
+            region,
+            e
+        );
+    }
+    if let Err(e) = insert_server_rounds(pool, frontend_game_name, region, platform, &rounds).await
+    {
+        log::error!(
+            "{} region {} failed to push server rounds: {:#?}",
+            frontend_game_name,
+            region,
+            e
+        );
+    }
+    match insert_servers(pool, frontend_game_name, region, platform, &server_infos).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            spool::store_failed(SpoolEntry::Timescale {
+                frontend_game_name: frontend_game_name.to_string(),
+                region: region.to_string(),
+                platform: platform.to_string(),
+                server_infos,
+            })
+            .await;
+            Err(e)
+        }
+    }
+}
+
+pub async fn insert_servers(
+    pool: &PgPool,
+    frontend_game_name: &str,
+    region: &str,
+    platform: &str,
+    server_infos: &[server_info::ServerInfo],
+) -> error::Result<()> {
     let mut server_names: Vec<String> = vec![];
     let mut soldier_amounts: Vec<i64> = vec![];
     let mut queue_amounts: Vec<i64> = vec![];
@@ -17,7 +88,9 @@ pub async fn push_server(
     let mut modes: Vec<Option<String>> = vec![];
     let mut maps: Vec<Option<String>> = vec![];
     let mut is_officials: Vec<Option<bool>> = vec![];
-    for server_info in server_infos {
+    let mut max_players: Vec<Option<i64>> = vec![];
+    let mut settings: Vec<Option<String>> = vec![];
+    for server_info in server_infos.iter().cloned() {
         if !server_info.name.is_empty() {
             server_names.push(server_info.name);
             soldier_amounts.push(server_info.soldiers);
@@ -39,13 +112,18 @@ pub async fn push_server(
                 false => None,
             });
             is_officials.push(server_info.is_official);
+            max_players.push(server_info.max_players);
+            settings.push(match !server_info.settings.is_empty() {
+                true => Some(server_info.settings.join(",")),
+                false => None,
+            });
         }
     }
 
     sqlx::query!(
         "
-            INSERT INTO battlefield_servers(game, region, platform, servername, is_official, game_id, guid, game_mode, game_map, soldier_amount, queue_amount) 
-            SELECT * FROM UNNEST($1::text[], $2::text[], $3::text[], $4::text[], $5::bool[], $6::text[], $7::text[], $8::text[], $9::text[], $10::int8[], $11::int8[])
+            INSERT INTO battlefield_servers(game, region, platform, servername, is_official, game_id, guid, game_mode, game_map, soldier_amount, queue_amount, max_players, settings) 
+            SELECT * FROM UNNEST($1::text[], $2::text[], $3::text[], $4::text[], $5::bool[], $6::text[], $7::text[], $8::text[], $9::text[], $10::int8[], $11::int8[], $12::int8[], $13::text[])
         ",
         &vec![frontend_game_name.to_string(); server_names.len()][..],
         &vec![region.to_string(); server_names.len()][..],
@@ -60,9 +138,203 @@ pub async fn push_server(
         &modes as &[Option<String>],
         &maps as &[Option<String>],
         &soldier_amounts[..],
-        &queue_amounts[..]
+        &queue_amounts[..],
+        &max_players as &[Option<i64>],
+        &settings as &[Option<String>],
     )
         .execute(pool)
         .await?;
     Ok(())
 }
+
+pub async fn insert_server_events(
+    pool: &PgPool,
+    frontend_game_name: &str,
+    region: &str,
+    platform: &str,
+    timestamp: &DateTime<Utc>,
+    events: &[ServerEvent],
+) -> error::Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let server_keys: Vec<String> = events
+        .iter()
+        .map(|event| event.server_key.clone())
+        .collect();
+    let server_names: Vec<String> = events.iter().map(|event| event.name.clone()).collect();
+    let kinds: Vec<String> = events
+        .iter()
+        .map(|event| event.kind.name().to_string())
+        .collect();
+    let details: Vec<Option<String>> = events.iter().map(|event| event.detail.clone()).collect();
+
+    sqlx::query!(
+        "
+            INSERT INTO server_events(game, region, platform, server_key, servername, event, detail, timestamp) 
+            SELECT *, $8::timestamptz FROM UNNEST($1::text[], $2::text[], $3::text[], $4::text[], $5::text[], $6::text[], $7::text[])
+        ",
+        &vec![frontend_game_name.to_string(); events.len()][..],
+        &vec![region.to_string(); events.len()][..],
+        &vec![platform.to_string(); events.len()][..],
+        &server_keys[..],
+        &server_names[..],
+        &kinds[..],
+        &details as &[Option<String>],
+        time::OffsetDateTime::from_unix_timestamp(timestamp.timestamp())
+            .map_err(anyhow::Error::from)?
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// "most played map by rounds" and "average round length" come from this table
+pub async fn insert_server_rounds(
+    pool: &PgPool,
+    frontend_game_name: &str,
+    region: &str,
+    platform: &str,
+    rounds: &[Round],
+) -> error::Result<()> {
+    if rounds.is_empty() {
+        return Ok(());
+    }
+    let mut server_keys: Vec<String> = vec![];
+    let mut server_names: Vec<String> = vec![];
+    let mut maps: Vec<String> = vec![];
+    let mut modes: Vec<String> = vec![];
+    let mut round_starts: Vec<time::OffsetDateTime> = vec![];
+    let mut round_ends: Vec<time::OffsetDateTime> = vec![];
+    let mut durations: Vec<i64> = vec![];
+    for round in rounds {
+        server_keys.push(round.server_key.clone());
+        server_names.push(round.name.clone());
+        maps.push(round.map.clone());
+        modes.push(round.mode.clone());
+        round_starts.push(
+            time::OffsetDateTime::from_unix_timestamp(round.start.timestamp())
+                .map_err(anyhow::Error::from)?,
+        );
+        round_ends.push(
+            time::OffsetDateTime::from_unix_timestamp(round.end.timestamp())
+                .map_err(anyhow::Error::from)?,
+        );
+        durations.push(round.duration_seconds());
+    }
+
+    sqlx::query!(
+        "
+            INSERT INTO server_rounds(game, region, platform, server_key, servername, game_map, game_mode, round_start, round_end, duration_seconds) 
+            SELECT * FROM UNNEST($1::text[], $2::text[], $3::text[], $4::text[], $5::text[], $6::text[], $7::text[], $8::timestamptz[], $9::timestamptz[], $10::int8[])
+        ",
+        &vec![frontend_game_name.to_string(); rounds.len()][..],
+        &vec![region.to_string(); rounds.len()][..],
+        &vec![platform.to_string(); rounds.len()][..],
+        &server_keys[..],
+        &server_names[..],
+        &maps[..],
+        &modes[..],
+        &round_starts[..],
+        &round_ends[..],
+        &durations[..],
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// upserts the playground tallies of a detailed run, experiences missing from the run drop to 0
+// so the trend shows them falling off once, and stays flat after that
+pub async fn push_playgrounds(
+    pool: &PgPool,
+    frontend_game_name: &str,
+    timestamp: &DateTime<Utc>,
+    result: &RegionResult,
+) -> error::Result<()> {
+    let mut config_names: Vec<String> = vec![];
+    let mut servers: Vec<i64> = vec![];
+    let mut players: Vec<i64> = vec![];
+    for (dimension, config_name, tally) in result.breakdown.iter() {
+        if dimension == Dimension::Playground && !config_name.is_empty() {
+            config_names.push(config_name.clone());
+            servers.push(tally.servers);
+            players.push(tally.players);
+        }
+    }
+    if config_names.is_empty() {
+        return Ok(());
+    }
+    let timestamp = time::OffsetDateTime::from_unix_timestamp(timestamp.timestamp())
+        .map_err(anyhow::Error::from)?;
+
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        "
+            INSERT INTO playground_leaderboard(game, config_name, servers, players, first_seen, last_seen) 
+            SELECT $1::text, config_name, servers, players, $5::timestamptz, $5::timestamptz FROM UNNEST($2::text[], $3::int8[], $4::int8[]) AS t(config_name, servers, players)
+            ON CONFLICT (game, config_name) DO UPDATE SET 
+                previous_players = playground_leaderboard.players, 
+                servers = EXCLUDED.servers, 
+                players = EXCLUDED.players, 
+                last_seen = EXCLUDED.last_seen
+        ",
+        frontend_game_name,
+        &config_names[..],
+        &servers[..],
+        &players[..],
+        timestamp
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        "
+            UPDATE playground_leaderboard SET previous_players = players, servers = 0, players = 0 
+            WHERE game = $1 AND last_seen < $2
+        ",
+        frontend_game_name,
+        timestamp
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+pub async fn playground_leaderboard(
+    pool: &PgPool,
+    frontend_game_name: &str,
+    limit: i64,
+) -> error::Result<Vec<PlaygroundEntry>> {
+    let rows = sqlx::query!(
+        "
+            SELECT config_name, servers, players, previous_players, first_seen, last_seen 
+            FROM playground_leaderboard WHERE game = $1 
+            ORDER BY players DESC, servers DESC LIMIT $2
+        ",
+        frontend_game_name,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| PlaygroundEntry {
+            trend: row
+                .previous_players
+                .map(|previous_players| row.players - previous_players),
+            config_name: row.config_name,
+            servers: row.servers,
+            players: row.players,
+            previous_players: row.previous_players,
+            first_seen: rfc3339(row.first_seen),
+            last_seen: rfc3339(row.last_seen),
+        })
+        .collect())
+}
+
+fn rfc3339(timestamp: time::OffsetDateTime) -> String {
+    DateTime::<Utc>::from_timestamp(timestamp.unix_timestamp(), 0)
+        .unwrap_or_default()
+        .to_rfc3339()
+}
@@ -1,10 +1,13 @@
 mod check_ea_desktop_session;
 mod connectors;
+mod error;
 mod gatherer;
+mod shutdown;
 mod structs;
 
 use gatherer::battlefield_grpc_bf2042;
 use grpc_rust::access_token::ea_desktop_access_token;
+use shutdown::Shutdown;
 use std::{
     collections::HashMap,
     env,
@@ -81,11 +84,20 @@ async fn main() -> anyhow::Result<()> {
     }
 
     let empty_game_hash: HashMap<String, String> = HashMap::new();
-    let mut sessions: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut sessions: HashMap<String, HashMap<String, String>> =
+        match mongo_client.get_sessions().await {
+            Ok(result) => result,
+            Err(e) => {
+                log::warn!("Failed to load stored sessions, {}", e);
+                HashMap::new()
+            }
+        };
 
     log::info!("Started");
 
-    loop {
+    let mut shutdown = Shutdown::listen();
+
+    while !shutdown.is_requested() {
         match battlefield_grpc_bf2042::check_session(
             sessions
                 .get("kingston")
@@ -97,6 +109,17 @@ async fn main() -> anyhow::Result<()> {
         .await
         {
             Ok(session) => {
+                if let Err(e) = mongo_client
+                    .push_sessions(
+                        "kingston",
+                        sessions.get("kingston").unwrap_or(&empty_game_hash),
+                        &session,
+                        true,
+                    )
+                    .await
+                {
+                    log::warn!("Failed to store session for kingston: {:#?}", e);
+                }
                 sessions.insert("kingston".to_string(), session);
                 last_update.store(
                     chrono::Utc::now().timestamp() / 60,
@@ -106,21 +129,31 @@ async fn main() -> anyhow::Result<()> {
             }
             Err(e) => {
                 log::error!("Failed kingston_grpc, auth_check reason: {:#?}", e);
-                match ea_desktop_access_token(bf2042_cookie.clone()).await {
-                    Ok(res) => {
-                        (ea_access_token, bf2042_cookie) = res;
-                        mongo_client
-                            .push_new_cookies(
-                                &api_bf2042_account,
-                                &bf2042_cookie,
-                                ea_access_token.clone(),
-                            )
-                            .await?;
-                    }
-                    Err(e) => log::error!("access_token for ea desktop failed: {:#?}", e),
-                };
+                // a rejected login needs a new access token
+                if e.is_auth() {
+                    match ea_desktop_access_token(bf2042_cookie.clone()).await {
+                        Ok(res) => {
+                            (ea_access_token, bf2042_cookie) = res;
+                            mongo_client
+                                .push_new_cookies(
+                                    &api_bf2042_account,
+                                    &bf2042_cookie,
+                                    ea_access_token.clone(),
+                                )
+                                .await?;
+                        }
+                        Err(e) => log::error!("access_token for ea desktop failed: {:#?}", e),
+                    };
+                }
             }
         };
-        sleep(Duration::from_secs(30)).await;
+        tokio::select! {
+            _ = sleep(Duration::from_secs(30)) => {}
+            _ = shutdown.requested() => {}
+        }
     }
+
+    log::info!("Shutting down...");
+    mongo_client.shutdown().await;
+    Ok(())
 }
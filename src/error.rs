@@ -0,0 +1,175 @@
+use influxdb2::models::data_point::DataPointError;
+use thiserror::Error;
+
+// what went wrong while gathering or writing, so callers can act on the kind
+// instead of only logging it
+#[derive(Debug, Error)]
+pub enum GatherError {
+    // needs a new session or access token
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    #[error(transparent)]
+    Network(reqwest::Error),
+    #[error("rate limited: {0}")]
+    RateLimited(String),
+    // the upstream response didn't match the expected schema
+    #[error("failed to parse response: {0}")]
+    Parse(String),
+    // unavailable when the sink couldn't be reached, instead of rejecting the write
+    #[error("failed to write to {sink}: {message}")]
+    Sink {
+        sink: &'static str,
+        message: String,
+        unavailable: bool,
+    },
+    #[error("{0}")]
+    UpstreamEmpty(String),
+    // the upstream data is older than allowed
+    #[error("stale data: {0}")]
+    Stale(String),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+pub type Result<T> = std::result::Result<T, GatherError>;
+
+impl GatherError {
+    // used as influx tag
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GatherError::Auth(_) => "auth",
+            GatherError::Network(_) => "network",
+            GatherError::RateLimited(_) => "rateLimited",
+            GatherError::Parse(_) => "parse",
+            GatherError::Sink { .. } => "sink",
+            GatherError::UpstreamEmpty(_) => "upstreamEmpty",
+            GatherError::Stale(_) => "stale",
+            GatherError::Other(_) => "other",
+        }
+    }
+
+    pub fn is_auth(&self) -> bool {
+        matches!(self, GatherError::Auth(_))
+    }
+
+    pub fn is_sink_unavailable(&self) -> bool {
+        matches!(
+            self,
+            GatherError::Sink {
+                unavailable: true,
+                ..
+            }
+        )
+    }
+}
+
+impl From<reqwest::Error> for GatherError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status().map(|status| status.as_u16()) {
+            Some(401) | Some(403) => GatherError::Auth(e.to_string()),
+            Some(429) => GatherError::RateLimited(e.to_string()),
+            _ if e.is_decode() => GatherError::Parse(e.to_string()),
+            _ => GatherError::Network(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for GatherError {
+    fn from(e: serde_json::Error) -> Self {
+        GatherError::Parse(e.to_string())
+    }
+}
+
+impl From<sqlx::Error> for GatherError {
+    fn from(e: sqlx::Error) -> Self {
+        let unavailable = match &e {
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => true,
+            // connection exceptions and shutdowns, like "the database system is starting up"
+            sqlx::Error::Database(database_error) => database_error
+                .code()
+                .is_some_and(|code| code.starts_with("08") || code.starts_with("57P")),
+            _ => false,
+        };
+        GatherError::Sink {
+            sink: "postgres",
+            message: e.to_string(),
+            unavailable,
+        }
+    }
+}
+
+impl From<influxdb2::RequestError> for GatherError {
+    fn from(e: influxdb2::RequestError) -> Self {
+        let unavailable = match &e {
+            influxdb2::RequestError::ReqwestProcessing { .. } => true,
+            influxdb2::RequestError::Http { status, .. } => {
+                status.is_server_error() || status.as_u16() == 429
+            }
+            _ => false,
+        };
+        GatherError::Sink {
+            sink: "influx",
+            message: e.to_string(),
+            unavailable,
+        }
+    }
+}
+
+impl From<DataPointError> for GatherError {
+    fn from(e: DataPointError) -> Self {
+        GatherError::Sink {
+            sink: "influx",
+            message: e.to_string(),
+            unavailable: false,
+        }
+    }
+}
+
+impl From<tonic::Status> for GatherError {
+    fn from(e: tonic::Status) -> Self {
+        match e.code() {
+            tonic::Code::Unauthenticated | tonic::Code::PermissionDenied => {
+                GatherError::Auth(e.to_string())
+            }
+            tonic::Code::ResourceExhausted => GatherError::RateLimited(e.to_string()),
+            _ => GatherError::Other(e.into()),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for GatherError {
+    fn from(e: mongodb::error::Error) -> Self {
+        GatherError::Other(e.into())
+    }
+}
+
+// keeps the kind of errors that passed through anyhow
+impl From<anyhow::Error> for GatherError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<GatherError>() {
+            Ok(gather_error) => return gather_error,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<reqwest::Error>() {
+            Ok(reqwest_error) => return reqwest_error.into(),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<serde_json::Error>() {
+            Ok(json_error) => return json_error.into(),
+            Err(e) => e,
+        };
+        // the grpc clients return their status errors through anyhow
+        let e = match e.downcast::<tonic::Status>() {
+            Ok(status) => return status.into(),
+            Err(e) => e,
+        };
+        match e.downcast::<sqlx::Error>() {
+            Ok(sqlx_error) => sqlx_error.into(),
+            Err(e) => GatherError::Other(e),
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::{
+    connectors::{influx_db, mongo::MongoClient},
+    error,
+    structs::results,
+};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Quarantined {
+    #[serde(rename = "serverAmount")]
+    pub server_amount: i64,
+    pub baseline: i64,
+    pub since: DateTime<Utc>,
+    pub cycles: u32,
+    #[serde(skip)]
+    pub platform_result: HashMap<String, results::RegionResult>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Publish,
+    Quarantine { server_amount: i64, baseline: i64 },
+    // the drop lasted ANOMALY_AUTO_ACCEPT_CYCLES runs, it's taken as the new normal
+    AutoAccept { server_amount: i64, baseline: i64 },
+}
+
+// published server amounts per game/platform (newest last) and the quarantined results,
+// shared with the status and accept endpoints. the baselines are stored in mongo, so a
+// restart doesn't have to learn them again
+#[derive(Debug, Clone)]
+pub struct Anomalies {
+    max_drop: f64,
+    min_baseline: i64,
+    window: usize,
+    auto_accept_cycles: u32,
+    baselines: Arc<Mutex<HashMap<String, VecDeque<i64>>>>,
+    quarantined: Arc<Mutex<HashMap<String, Quarantined>>>,
+}
+
+fn key(frontend_game_name: &str, platform: &str) -> String {
+    format!("{}/{}", frontend_game_name, platform)
+}
+
+fn server_amount(platform_result: &HashMap<String, results::RegionResult>) -> i64 {
+    match platform_result.get("ALL") {
+        Some(all_regions) => all_regions.amounts.server_amount,
+        None => platform_result
+            .values()
+            .map(|region| region.amounts.server_amount)
+            .sum(),
+    }
+}
+
+fn median(values: &VecDeque<i64>) -> i64 {
+    let mut sorted: Vec<i64> = values.iter().copied().collect();
+    sorted.sort_unstable();
+    sorted[sorted.len() / 2]
+}
+
+impl Anomalies {
+    pub fn new(max_drop: f64, min_baseline: i64, window: usize, auto_accept_cycles: u32) -> Self {
+        Anomalies {
+            max_drop,
+            min_baseline,
+            window: window.max(1),
+            auto_accept_cycles: auto_accept_cycles.max(1),
+            baselines: Arc::new(Mutex::new(HashMap::new())),
+            quarantined: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // a drop bigger than ANOMALY_MAX_DROP (0.6 = 60%) gets quarantined, small games swing
+    // too much to judge
+    pub fn from_env() -> Self {
+        Anomalies::new(
+            env::var("ANOMALY_MAX_DROP")
+                .ok()
+                .and_then(|drop| drop.parse::<f64>().ok())
+                .unwrap_or(0.6),
+            env::var("ANOMALY_MIN_SERVERS")
+                .ok()
+                .and_then(|servers| servers.parse::<i64>().ok())
+                .unwrap_or(10),
+            env::var("ANOMALY_WINDOW")
+                .ok()
+                .and_then(|window| window.parse::<usize>().ok())
+                .unwrap_or(6),
+            // 12 runs are an hour with the 5 minute runs
+            env::var("ANOMALY_AUTO_ACCEPT_CYCLES")
+                .ok()
+                .and_then(|cycles| cycles.parse::<u32>().ok())
+                .unwrap_or(12),
+        )
+    }
+
+    fn push_baseline(
+        &self,
+        baselines: &mut HashMap<String, VecDeque<i64>>,
+        key: String,
+        amount: i64,
+    ) {
+        let baseline = baselines.entry(key).or_default();
+        baseline.push_back(amount);
+        while baseline.len() > self.window {
+            baseline.pop_front();
+        }
+    }
+
+    // compares the result to the median of the last published cycles
+    pub fn check(
+        &self,
+        frontend_game_name: &str,
+        platform: &str,
+        platform_result: &HashMap<String, results::RegionResult>,
+    ) -> Verdict {
+        let key = key(frontend_game_name, platform);
+        let amount = server_amount(platform_result);
+        let mut baselines = self
+            .baselines
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut quarantined = self
+            .quarantined
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let baseline = match baselines.get(&key) {
+            Some(values) if values.len() >= 3 => median(values),
+            _ => {
+                self.push_baseline(&mut baselines, key, amount);
+                return Verdict::Publish;
+            }
+        };
+
+        if baseline < self.min_baseline
+            || (amount as f64) >= baseline as f64 * (1.0 - self.max_drop)
+        {
+            self.push_baseline(&mut baselines, key.clone(), amount);
+            quarantined.remove(&key);
+            return Verdict::Publish;
+        }
+
+        let cycles = quarantined
+            .get(&key)
+            .map(|quarantined| quarantined.cycles + 1)
+            .unwrap_or(1);
+        if cycles >= self.auto_accept_cycles {
+            quarantined.remove(&key);
+            baselines.insert(key, VecDeque::from([amount]));
+            return Verdict::AutoAccept {
+                server_amount: amount,
+                baseline,
+            };
+        }
+        quarantined
+            .entry(key)
+            .and_modify(|quarantined| {
+                quarantined.server_amount = amount;
+                quarantined.cycles = cycles;
+                quarantined.platform_result = platform_result.to_owned();
+            })
+            .or_insert(Quarantined {
+                server_amount: amount,
+                baseline,
+                since: Utc::now(),
+                cycles,
+                platform_result: platform_result.to_owned(),
+            });
+        Verdict::Quarantine {
+            server_amount: amount,
+            baseline,
+        }
+    }
+
+    // quarantined results go to ANOMALY_QUARANTINE_BUCKET instead of the published one
+    pub async fn publish(
+        &self,
+        influx_client: &influxdb2::Client,
+        frontend_game_name: &str,
+        platform: &str,
+        platform_result: &HashMap<String, results::RegionResult>,
+    ) -> error::Result<()> {
+        match self.check(frontend_game_name, platform, platform_result) {
+            Verdict::Publish => {}
+            Verdict::Quarantine {
+                server_amount,
+                baseline,
+            } => {
+                log::error!(
+                    "{} {} quarantined, {} servers against a baseline of {}. accept it with POST /anomaly/accept/{}/{}",
+                    frontend_game_name,
+                    platform,
+                    server_amount,
+                    baseline,
+                    frontend_game_name,
+                    platform
+                );
+                return influx_db::push_quarantined(
+                    influx_client,
+                    frontend_game_name,
+                    platform,
+                    platform_result,
+                )
+                .await;
+            }
+            Verdict::AutoAccept {
+                server_amount,
+                baseline,
+            } => log::warn!(
+                "{} {} stayed at {} servers against a baseline of {}, accepting it",
+                frontend_game_name,
+                platform,
+                server_amount,
+                baseline
+            ),
+        };
+        influx_db::push_to_database(influx_client, frontend_game_name, platform, platform_result)
+            .await
+    }
+
+    // accepts the latest quarantined result as real, it becomes the new baseline
+    pub fn accept(
+        &self,
+        frontend_game_name: &str,
+        platform: &str,
+    ) -> Option<HashMap<String, results::RegionResult>> {
+        let key = key(frontend_game_name, platform);
+        let quarantined = self
+            .quarantined
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&key)?;
+        self.baselines
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, VecDeque::from([quarantined.server_amount]));
+        Some(quarantined.platform_result)
+    }
+
+    pub fn quarantined(&self) -> HashMap<String, Quarantined> {
+        self.quarantined
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn is_quarantined(&self, frontend_game_name: &str, platform: &str) -> bool {
+        self.quarantined
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains_key(&key(frontend_game_name, platform))
+    }
+
+    pub async fn load(&self, mongo_client: &mut MongoClient) -> anyhow::Result<usize> {
+        let stored = mongo_client.get_anomaly_baselines().await?;
+        let amount = stored.len();
+        *self
+            .baselines
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = stored
+            .into_iter()
+            .map(|(key, server_amounts)| (key, VecDeque::from(server_amounts)))
+            .collect();
+        Ok(amount)
+    }
+
+    pub async fn save(&self, mongo_client: &mut MongoClient) -> anyhow::Result<()> {
+        let baselines: HashMap<String, Vec<i64>> = self
+            .baselines
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(key, server_amounts)| (key.clone(), server_amounts.iter().copied().collect()))
+            .collect();
+        mongo_client.push_anomaly_baselines(&baselines).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn platform_result(servers: i64) -> HashMap<String, results::RegionResult> {
+        let mut all_regions = results::combine_region_players("ALL", "pc", &HashMap::new()).await;
+        all_regions.amounts.server_amount = servers;
+        HashMap::from([("ALL".to_string(), all_regions)])
+    }
+
+    // max drop 50%, at least 10 servers, 4 cycles to auto accept
+    async fn learned(anomalies: &Anomalies, servers: i64) {
+        for _ in 0..3 {
+            assert_eq!(
+                anomalies.check("bf4", "pc", &platform_result(servers).await),
+                Verdict::Publish
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn publishes_while_learning_and_small_drops() {
+        let anomalies = Anomalies::new(0.5, 10, 6, 4);
+        // nothing to compare to yet
+        learned(&anomalies, 100).await;
+        assert_eq!(
+            anomalies.check("bf4", "pc", &platform_result(50).await),
+            Verdict::Publish
+        );
+        assert!(!anomalies.is_quarantined("bf4", "pc"));
+    }
+
+    #[tokio::test]
+    async fn quarantines_big_drops_until_recovered() {
+        let anomalies = Anomalies::new(0.5, 10, 6, 4);
+        learned(&anomalies, 100).await;
+        assert_eq!(
+            anomalies.check("bf4", "pc", &platform_result(20).await),
+            Verdict::Quarantine {
+                server_amount: 20,
+                baseline: 100
+            }
+        );
+        assert!(anomalies.is_quarantined("bf4", "pc"));
+        assert!(!anomalies.is_quarantined("bf4", "ps4"));
+        assert_eq!(anomalies.quarantined()["bf4/pc"].cycles, 1);
+
+        assert_eq!(
+            anomalies.check("bf4", "pc", &platform_result(95).await),
+            Verdict::Publish
+        );
+        assert!(!anomalies.is_quarantined("bf4", "pc"));
+    }
+
+    #[tokio::test]
+    async fn ignores_small_games() {
+        let anomalies = Anomalies::new(0.5, 10, 6, 4);
+        learned(&anomalies, 8).await;
+        assert_eq!(
+            anomalies.check("bf4", "pc", &platform_result(0).await),
+            Verdict::Publish
+        );
+    }
+
+    #[tokio::test]
+    async fn auto_accepts_lasting_drops() {
+        let anomalies = Anomalies::new(0.5, 10, 6, 4);
+        learned(&anomalies, 100).await;
+        for cycles in 1..4 {
+            assert!(matches!(
+                anomalies.check("bf4", "pc", &platform_result(20).await),
+                Verdict::Quarantine { .. }
+            ));
+            assert_eq!(anomalies.quarantined()["bf4/pc"].cycles, cycles);
+        }
+        assert_eq!(
+            anomalies.check("bf4", "pc", &platform_result(20).await),
+            Verdict::AutoAccept {
+                server_amount: 20,
+                baseline: 100
+            }
+        );
+        assert!(!anomalies.is_quarantined("bf4", "pc"));
+        // the drop is the new baseline
+        assert_eq!(
+            anomalies.check("bf4", "pc", &platform_result(20).await),
+            Verdict::Publish
+        );
+    }
+
+    #[tokio::test]
+    async fn accept_makes_the_drop_the_baseline() {
+        let anomalies = Anomalies::new(0.5, 10, 6, 4);
+        learned(&anomalies, 100).await;
+        anomalies.check("bf4", "pc", &platform_result(20).await);
+        let accepted = anomalies.accept("bf4", "pc").unwrap();
+        assert_eq!(accepted["ALL"].amounts.server_amount, 20);
+        assert!(!anomalies.is_quarantined("bf4", "pc"));
+        assert!(anomalies.accept("bf4", "pc").is_none());
+        // relearning from the accepted amount
+        learned(&anomalies, 20).await;
+        assert!(matches!(
+            anomalies.check("bf4", "pc", &platform_result(5).await),
+            Verdict::Quarantine { baseline: 20, .. }
+        ));
+    }
+}
@@ -1,40 +1,38 @@
+use super::{
+    anomaly::Anomalies,
+    http::HttpClient,
+    retry::{retry, RetryPolicy},
+    validation,
+};
 use crate::{
-    connectors::{influx_db, timescale_db::push_server},
+    connectors::timescale_db::push_server,
+    error,
     structs::{battlebit::BattlebitServer, results, server_info},
 };
 use chrono::Utc;
 use sqlx::PgPool;
 use std::collections::HashMap;
 
-async fn gather_servers() -> Vec<crate::structs::battlebit::BattlebitServer> {
-    let client = reqwest::Client::new();
+async fn gather_servers(client: &HttpClient) -> error::Result<Vec<BattlebitServer>> {
+    let policy = RetryPolicy::from_env();
     let url = "https://publicapi.battlebit.cloud/Servers/GetServerList";
-    match client.get(url).send().await {
-        Ok(resp) => {
-            let mut json_string = resp.text().await.unwrap_or_default();
-            // remove weird 0 width character
-            // https://github.com/seanmonstar/reqwest/issues/426
-            let json_bytes = json_string.as_bytes();
-            if json_bytes[0] == 239 {
-                json_string.remove(0);
-            }
-            match serde_json::from_str::<Vec<crate::structs::battlebit::BattlebitServer>>(
-                &json_string,
-            ) {
-                Ok(json_res) => {
-                    return json_res;
-                }
-                Err(e) => {
-                    log::error!("BattleBit public json is incorrect: {:#?}", e);
-                    return vec![];
-                }
-            }
-        }
-        Err(e) => {
-            log::error!("Battlebit public url failed: {:#?}", e);
-            return vec![];
+    retry(&policy, "battlebit public url", || async move {
+        let _permit = client.permit(url).await?;
+        let mut json_string = client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        // remove weird 0 width character
+        // https://github.com/seanmonstar/reqwest/issues/426
+        if json_string.as_bytes().first() == Some(&239) {
+            json_string.remove(0);
         }
-    }
+        Ok(serde_json::from_str::<Vec<BattlebitServer>>(&json_string)?)
+    })
+    .await
 }
 
 async fn server_list_to_sum(
@@ -82,17 +80,11 @@ async fn server_list_to_sum(
             dice_queue_amount: 0,
             dice_spectator_amount: 0,
             community_spectator_amount: 0,
+            slot_amount: 0,
+            slotted_soldier_amount: 0,
         },
-        maps: HashMap::new(),
-        modes: HashMap::new(),
+        breakdown: results::Breakdown::default(),
         timestamp: Utc::now(),
-        map_players: HashMap::new(),
-        mode_players: HashMap::new(),
-        settings: HashMap::new(),
-        settings_players: HashMap::new(),
-        owner_platform: HashMap::new(),
-        playground: HashMap::new(),
-        playground_players: HashMap::new(),
     };
     let mut regions: HashMap<String, results::RegionResult> = HashMap::new();
     let mut server_stats: HashMap<String, Vec<server_info::ServerInfo>> = HashMap::new();
@@ -106,6 +98,8 @@ async fn server_list_to_sum(
             .get(&server.region[..])
             .unwrap_or(&&server.region[..])
             .to_string();
+        let max_players = server_info::capacity(server.max_players);
+        let settings = server.settings();
 
         regions
             .entry(server_region.to_string())
@@ -113,26 +107,24 @@ async fn server_list_to_sum(
                 region.amounts.server_amount += 1;
                 region.amounts.soldier_amount += server.players;
                 region.amounts.queue_amount += server.queue_players;
+                region.amounts.add_slots(server.players, max_players);
+                region.breakdown.add(
+                    results::Dimension::Map,
+                    &server.map,
+                    server.players,
+                    max_players,
+                );
                 region
-                    .maps
-                    .entry(server.map.clone())
-                    .and_modify(|count| *count += 1)
-                    .or_insert(1);
-                region
-                    .map_players
-                    .entry(server.map.clone())
-                    .and_modify(|count| *count += server.players)
-                    .or_insert(server.players);
-                region
-                    .modes
-                    .entry(mode.clone())
-                    .and_modify(|count| *count += 1)
-                    .or_insert(1);
-                region
-                    .mode_players
-                    .entry(mode.clone())
-                    .and_modify(|count| *count += server.players)
-                    .or_insert(server.players);
+                    .breakdown
+                    .add(results::Dimension::Mode, &mode, server.players, max_players);
+                for setting in &settings {
+                    region.breakdown.add(
+                        results::Dimension::Setting,
+                        setting,
+                        server.players,
+                        max_players,
+                    );
+                }
                 if server.is_official {
                     region.amounts.dice_server_amount += 1;
                     region.amounts.dice_soldier_amount += server.players;
@@ -180,44 +172,49 @@ async fn server_list_to_sum(
                         spectator_amount: 0,
                         dice_spectator_amount: 0,
                         community_spectator_amount: 0,
+                        slot_amount: max_players.unwrap_or_default(),
+                        slotted_soldier_amount: max_players.map_or(0, |_| server.players),
                     },
-                    maps: HashMap::from([(server.map.clone(), 1)]),
-                    modes: HashMap::from([(mode.clone(), 1)]),
+                    breakdown: results::Breakdown::server(
+                        [
+                            (results::Dimension::Map, &server.map[..]),
+                            (results::Dimension::Mode, &mode[..]),
+                        ]
+                        .into_iter()
+                        .chain(
+                            settings
+                                .iter()
+                                .map(|setting| (results::Dimension::Setting, &setting[..])),
+                        ),
+                        server.players,
+                        max_players,
+                    ),
                     timestamp: Utc::now(),
-                    map_players: HashMap::from([(server.map.clone(), server.players)]),
-                    mode_players: HashMap::from([(mode.clone(), server.players)]),
-                    settings: HashMap::new(),
-                    settings_players: HashMap::new(),
-                    owner_platform: HashMap::new(),
-                    playground: HashMap::new(),
-                    playground_players: HashMap::new(),
                 }
             });
 
+        all_regions.breakdown.add(
+            results::Dimension::Map,
+            &server.map,
+            server.players,
+            max_players,
+        );
         all_regions
-            .maps
-            .entry(server.map.clone())
-            .and_modify(|count| *count += 1)
-            .or_insert(1);
-        all_regions
-            .map_players
-            .entry(server.map.clone())
-            .and_modify(|count| *count += server.players)
-            .or_insert(server.players);
-        all_regions
-            .modes
-            .entry(mode.clone())
-            .and_modify(|count| *count += 1)
-            .or_insert(1);
-        all_regions
-            .mode_players
-            .entry(mode.clone())
-            .and_modify(|count| *count += server.players)
-            .or_insert(server.players);
+            .breakdown
+            .add(results::Dimension::Mode, &mode, server.players, max_players);
+        for setting in &settings {
+            all_regions.breakdown.add(
+                results::Dimension::Setting,
+                setting,
+                server.players,
+                max_players,
+            );
+        }
 
         all_regions.amounts.server_amount += 1;
         all_regions.amounts.soldier_amount += server.players;
         all_regions.amounts.queue_amount += server.queue_players;
+        all_regions.amounts.add_slots(server.players, max_players);
         if server.is_official {
             all_regions.amounts.dice_server_amount += 1;
             all_regions.amounts.dice_soldier_amount += server.players;
@@ -237,6 +234,8 @@ async fn server_list_to_sum(
             map: server.map,
             game_id: "".to_owned(),
             is_official: Some(server.is_official),
+            max_players,
+            settings,
         };
         server_stats
             .entry(server.region.to_string())
@@ -249,10 +248,13 @@ async fn server_list_to_sum(
 }
 
 pub async fn push_battlebit(
+    client: &HttpClient,
     pool: &PgPool,
     influx_client: &influxdb2::Client,
-) -> anyhow::Result<()> {
-    let found_servers = gather_servers().await;
+    anomalies: &Anomalies,
+) -> error::Result<()> {
+    let found_servers = gather_servers(client).await?;
+    let found_servers = validation::validate(influx_client, "battlebit", "pc", found_servers).await;
     let (regions, server_stats) = server_list_to_sum(found_servers).await;
     for (region, server_stat) in server_stats {
         match push_server(pool, "battlebit", &region, "pc", server_stat).await {
@@ -264,7 +266,10 @@ pub async fn push_battlebit(
             ),
         };
     }
-    match influx_db::push_to_database(influx_client, "battlebit", "pc", &regions).await {
+    match anomalies
+        .publish(influx_client, "battlebit", "pc", &regions)
+        .await
+    {
         Ok(_) => {}
         Err(e) => log::error!("battlebit failed to push to influxdb: {:#?}", e),
     };
@@ -14,7 +14,9 @@ use std::collections::HashMap;
 use tokio::task::JoinSet;
 
 use crate::{
-    connectors::{influx_db, timescale_db::push_server},
+    connectors::timescale_db::{self, push_server},
+    error::{self, GatherError},
+    gatherer::{anomaly::Anomalies, validation},
     structs::{results, server_info},
 };
 
@@ -22,7 +24,7 @@ pub async fn check_session(
     mut sessions: HashMap<String, String>,
     cookie: bf_sparta::cookie::Cookie,
     ea_access_token: String,
-) -> anyhow::Result<HashMap<String, String>> {
+) -> error::Result<HashMap<String, String>> {
     let mut santiago_client =
         SantiagoClient::new(sessions.get("pc").unwrap_or(&"".to_string()).to_string()).await?;
     match santiago_client
@@ -30,7 +32,12 @@ pub async fn check_session(
         .await
     {
         Ok(_) => {}
-        Err(e) => anyhow::bail!("santiago session failed: {:#?}", e),
+        Err(e) => {
+            return Err(GatherError::Auth(format!(
+                "santiago session failed: {:#?}",
+                e
+            )))
+        }
     };
     let servers = Play::get_filtered_game_servers(
         &santiago_client,
@@ -68,7 +75,9 @@ pub async fn check_session(
         None => false,
     };
     if !has_result {
-        anyhow::bail!("santiago: no servers on auth check");
+        return Err(GatherError::UpstreamEmpty(
+            "santiago: no servers on auth check".to_string(),
+        ));
     }
     sessions.insert("pc".into(), santiago_client.session_id);
     Ok(sessions)
@@ -80,7 +89,7 @@ async fn region_players(
     region: String,
     aws_regions: Vec<String>,
     run_detailed: bool,
-) -> anyhow::Result<(String, results::RegionResult)> {
+) -> anyhow::Result<(String, results::RegionResult, HashMap<&'static str, i64>)> {
     let bf6_maps = HashMap::from([
         ("MP_Abbasid", "SiegeOfCairo"),
         ("MP_Aftermath", "EmpireState"),
@@ -111,6 +120,7 @@ async fn region_players(
         (7, "steam"),
     ]);
     let mut server_stats = vec![];
+    let mut validation = validation::Validation::new(validation::Validator::from_env());
 
     let mut region_stats: results::RegionResult = results::RegionResult {
         metadata: results::Metadata {
@@ -130,17 +140,11 @@ async fn region_players(
             community_soldier_amount: 0,
             community_queue_amount: 0,
             community_spectator_amount: 0,
+            slot_amount: 0,
+            slotted_soldier_amount: 0,
         },
-        maps: HashMap::new(),
-        modes: HashMap::new(),
-        settings: HashMap::new(),
-        owner_platform: HashMap::new(),
+        breakdown: results::Breakdown::default(),
         timestamp: Utc::now(),
-        map_players: HashMap::new(),
-        mode_players: HashMap::new(),
-        settings_players: HashMap::new(),
-        playground: HashMap::new(),
-        playground_players: HashMap::new(),
     };
 
     for aws_region in aws_regions {
@@ -168,15 +172,27 @@ async fn region_players(
                         None => ServerListResponseInner { servers: vec![] },
                     };
                     for server in server_list.servers {
-                        let server_map = bf6_maps
-                            .get(&server.current_map[..])
-                            .unwrap_or(&"")
-                            .to_string();
-                        let server_mode =
-                            bf6_modes.get(&server.mode[..]).unwrap_or(&"").to_string();
-
-                        let soldier_amount =
-                            server.players.unwrap_or_default().player_amount as i64;
+                        let mut server_info = server_info::ServerInfo {
+                            game_id: server.blaze_game_id.to_string(),
+                            guid: server.server_id.clone(),
+                            name: server.prefix,
+                            soldiers: server.players.unwrap_or_default().player_amount as i64,
+                            queue: 0,
+                            mode: bf6_modes.get(&server.mode[..]).unwrap_or(&"").to_string(),
+                            map: bf6_maps
+                                .get(&server.current_map[..])
+                                .unwrap_or(&"")
+                                .to_string(),
+                            is_official: None,
+                            // the server list doesn't include the capacity
+                            max_players: None,
+                            settings: vec![],
+                        };
+                        if !validation.check(&mut server_info) {
+                            continue;
+                        }
+                        let soldier_amount = server_info.soldiers;
+                        let max_players = server_info.max_players;
 
                         if run_detailed {
                             match Play::get_detailed_server_info(
@@ -190,16 +206,12 @@ async fn region_players(
                                 Ok(result) => {
                                     if let Some(current) = result.server_info {
                                         if let Some(current_server_info) = current.server_info {
-                                            region_stats
-                                                .playground
-                                                .entry(current_server_info.config_name.clone())
-                                                .and_modify(|count| *count += 1)
-                                                .or_insert(1);
-                                            region_stats
-                                                .playground_players
-                                                .entry(current_server_info.config_name)
-                                                .and_modify(|count| *count += soldier_amount)
-                                                .or_insert(soldier_amount);
+                                            region_stats.breakdown.add(
+                                                results::Dimension::Playground,
+                                                &current_server_info.config_name,
+                                                soldier_amount,
+                                                max_players,
+                                            );
                                         }
                                     }
                                 }
@@ -209,56 +221,51 @@ async fn region_players(
 
                         region_stats.amounts.server_amount += 1;
                         region_stats.amounts.soldier_amount += soldier_amount;
+                        region_stats.amounts.add_slots(soldier_amount, max_players);
 
-                        region_stats
-                            .maps
-                            .entry(server_map.clone())
-                            .and_modify(|count| *count += 1)
-                            .or_insert(1);
-                        region_stats
-                            .map_players
-                            .entry(server_map.clone())
-                            .and_modify(|count| *count += soldier_amount)
-                            .or_insert(soldier_amount);
-                        region_stats
-                            .modes
-                            .entry(server_mode.clone())
-                            .and_modify(|count| *count += 1)
-                            .or_insert(1);
-                        region_stats
-                            .mode_players
-                            .entry(server_mode.clone())
-                            .and_modify(|count| *count += soldier_amount)
-                            .or_insert(soldier_amount);
-                        region_stats
-                            .owner_platform
-                            .entry(
-                                bf6_platform
-                                    .get(&server.owner.unwrap_or_default().platform_id)
-                                    .unwrap_or(&"")
-                                    .to_string(),
-                            )
-                            .and_modify(|count| *count += 1)
-                            .or_insert(1);
+                        region_stats.breakdown.add(
+                            results::Dimension::Map,
+                            &server_info.map,
+                            soldier_amount,
+                            max_players,
+                        );
+                        region_stats.breakdown.add(
+                            results::Dimension::Mode,
+                            &server_info.mode,
+                            soldier_amount,
+                            max_players,
+                        );
+                        region_stats.breakdown.add(
+                            results::Dimension::PingSite,
+                            &aws_region,
+                            soldier_amount,
+                            max_players,
+                        );
+                        region_stats.breakdown.add(
+                            results::Dimension::OwnerPlatform,
+                            bf6_platform
+                                .get(&server.owner.unwrap_or_default().platform_id)
+                                .unwrap_or(&""),
+                            soldier_amount,
+                            max_players,
+                        );
 
-                        server_stats.push(server_info::ServerInfo {
-                            game_id: server.blaze_game_id.to_string(),
-                            guid: server.server_id,
-                            name: server.prefix,
-                            soldiers: soldier_amount,
-                            queue: 0,
-                            mode: server_mode,
-                            map: server_map,
-                            is_official: None,
-                        });
+                        server_stats.push(server_info);
                     }
                 }
-                Err(e) => log::error!(
-                    "{} santiago region failed with map {}: {:#?}",
-                    aws_region,
-                    map,
-                    e
-                ),
+                Err(e) => {
+                    // a rejected access token fails every call, the run needs a new login
+                    let e = GatherError::from(e);
+                    if e.is_auth() {
+                        return Err(e.into());
+                    }
+                    log::error!(
+                        "{} santiago region failed with map {}: {:#?}",
+                        aws_region,
+                        map,
+                        e
+                    )
+                }
             };
         }
     }
@@ -272,14 +279,17 @@ async fn region_players(
         ),
     };
 
-    Ok((region, region_stats))
+    Ok((region, region_stats, validation.rejections))
 }
 
 async fn get_region_stats(
     pool: &PgPool,
     santiago_client: &SantiagoClient,
     run_detailed: bool,
-) -> anyhow::Result<HashMap<String, results::RegionResult>> {
+) -> anyhow::Result<(
+    HashMap<String, results::RegionResult>,
+    HashMap<&'static str, i64>,
+)> {
     let grpc_regions = HashMap::from([
         (
             "Asia",
@@ -313,6 +323,7 @@ async fn get_region_stats(
     ]);
 
     let mut region_result: HashMap<String, results::RegionResult> = HashMap::new();
+    let mut rejections: HashMap<&'static str, i64> = HashMap::new();
 
     let mut set = JoinSet::new();
     for (region, aws_regions) in grpc_regions {
@@ -328,10 +339,17 @@ async fn get_region_stats(
     while let Some(res) = set.join_next().await {
         let out = res?;
         match out {
-            Ok((region, region_stats)) => {
+            Ok((region, region_stats, region_rejections)) => {
                 region_result.insert(region.to_string(), region_stats);
+                for (rule, amount) in region_rejections {
+                    *rejections.entry(rule).or_insert(0) += amount;
+                }
             }
             Err(e) => {
+                let e = GatherError::from(e);
+                if e.is_auth() {
+                    return Err(e.into());
+                }
                 log::error!("santiago region failed, with reason: {:#?}", e);
             }
         }
@@ -339,17 +357,18 @@ async fn get_region_stats(
 
     let all_regions = results::combine_region_players("ALL", "global", &region_result).await;
     region_result.insert("ALL".to_string(), all_regions);
-    Ok(region_result)
+    Ok((region_result, rejections))
 }
 
 pub async fn gather_grpc(
     pool: &PgPool,
     influx_client: &influxdb2::Client,
+    anomalies: &Anomalies,
     mut sessions: HashMap<String, String>,
     cookie: bf_sparta::cookie::Cookie,
     run_detailed: bool,
     ea_access_token: String,
-) -> anyhow::Result<(HashMap<String, String>, results::RegionResult)> {
+) -> error::Result<(HashMap<String, String>, results::RegionResult)> {
     let mut santiago_client =
         SantiagoClient::new(sessions.get("pc").unwrap_or(&"".to_string()).to_string()).await?;
     match santiago_client
@@ -357,24 +376,43 @@ pub async fn gather_grpc(
         .await
     {
         Ok(_) => {}
-        Err(e) => anyhow::bail!("santiago session failed: {:#?}", e),
+        Err(e) => {
+            return Err(GatherError::Auth(format!(
+                "santiago session failed: {:#?}",
+                e
+            )))
+        }
     };
     let game_result = match get_region_stats(pool, &santiago_client, run_detailed).await {
-        Ok(result) => {
+        Ok((result, rejections)) => {
+            validation::report(influx_client, "bf6portal", "global", &rejections).await;
             // influx
-            match influx_db::push_to_database(influx_client, "bf6portal", "global", &result).await {
+            match anomalies
+                .publish(influx_client, "bf6portal", "global", &result)
+                .await
+            {
                 Ok(_) => {}
                 Err(e) => log::error!("santiago failed to push to influxdb: {:#?}", e),
             };
             result
         }
-        Err(e) => anyhow::bail!("santiago gather failed: {:#?}", e),
+        Err(e) => return Err(e.into()),
     };
     sessions.insert("pc".into(), santiago_client.session_id);
     let result = match game_result.get("ALL") {
         Some(result) => result,
-        None => anyhow::bail!("santiago has no ALL region!"),
+        None => {
+            return Err(GatherError::UpstreamEmpty(
+                "santiago has no ALL region!".to_string(),
+            ))
+        }
     };
+    if run_detailed {
+        match timescale_db::push_playgrounds(pool, "bf6", &result.timestamp, result).await {
+            Ok(_) => {}
+            Err(e) => log::error!("santiago failed to push playground leaderboard: {:#?}", e),
+        };
+    }
 
     Ok((sessions, result.to_owned()))
 }
@@ -1,49 +1,121 @@
 use chrono::Utc;
-use futures::future::join_all;
-use reqwest::header::HeaderMap;
+use futures::{stream, StreamExt};
+use reqwest::header::{HeaderMap, HeaderValue};
 use sqlx::PgPool;
-use std::collections::HashMap;
+use std::{collections::HashMap, env, time::Duration};
 
+use super::{
+    anomaly::Anomalies,
+    geoip,
+    http::HttpClient,
+    retry::{retry, RetryPolicy},
+    validation,
+};
 use crate::{
     connectors::{influx_db, timescale_db::push_server},
+    error::{self, GatherError},
     structs::{
-        battlelog::BattlelogServer,
+        battlelog::{BattlelogServer, CrawlReport, Keeper, KeeperSummary, KeeperVerification},
         results,
         server_info::{self, ServerInfo},
     },
 };
 
-async fn get_battlelog_keeper_data(guid: &String) -> anyhow::Result<(&String, usize)> {
-    let client = reqwest::Client::new();
+async fn get_battlelog_keeper_data(
+    client: &HttpClient,
+    guid: &str,
+) -> anyhow::Result<KeeperSummary> {
     let url = format!("https://keeper.battlelog.com/snapshot/{guid}");
-    let mut players = 0;
-    match client.get(url).send().await {
-        Ok(resp) => match resp.json::<crate::structs::battlelog::Keeper>().await {
-            Ok(json_res) => {
-                for item in json_res.snapshot.team_info.values() {
-                    players += item.players.len();
+    let _permit = client.permit(&url).await?;
+    let json_res = client
+        .get(&url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Keeper>()
+        .await?;
+    Ok(json_res.into())
+}
+
+// checks the soldier count of every populated server against its keeper snapshot,
+// servers whose snapshot failed keep the count from the server list
+async fn verify_with_keeper(
+    client: &HttpClient,
+    game_name: &str,
+    found_servers: &mut HashMap<String, BattlelogServer>,
+) -> KeeperVerification {
+    let concurrency = env::var("BATTLELOG_KEEPER_CONCURRENCY")
+        .ok()
+        .and_then(|limit| limit.parse::<usize>().ok())
+        .unwrap_or(10)
+        .max(1);
+    let request_timeout = Duration::from_secs(
+        env::var("BATTLELOG_KEEPER_TIMEOUT_SECONDS")
+            .ok()
+            .and_then(|secs| secs.parse::<u64>().ok())
+            .unwrap_or(15),
+    );
+
+    let guids: Vec<String> = found_servers
+        .values()
+        .filter(|server| server.soldier_amount > 0)
+        .map(|server| server.guid.clone())
+        .collect();
+    let results: Vec<(String, anyhow::Result<KeeperSummary>)> = stream::iter(guids)
+        .map(|guid| async move {
+            let result = match tokio::time::timeout(
+                request_timeout,
+                get_battlelog_keeper_data(client, &guid),
+            )
+            .await
+            {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::anyhow!("timed out after {:?}", request_timeout)),
+            };
+            (guid, result)
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let mut verification = KeeperVerification {
+        checked: results.len() as i64,
+        failed: 0,
+        team_difference: 0,
+        ticket_difference: 0,
+        ticket_servers: 0,
+    };
+    for (guid, result) in results {
+        match result {
+            Ok(summary) => {
+                if let Some(found_server) = found_servers.get_mut(&guid) {
+                    found_server.soldier_amount = summary.soldiers;
+                }
+                verification.team_difference += summary.team_difference;
+                if let Some(ticket_difference) = summary.ticket_difference {
+                    verification.ticket_difference += ticket_difference;
+                    verification.ticket_servers += 1;
                 }
             }
             Err(e) => {
                 log::error!(
-                    "bf4 failed to read json of snapshot of guid {}: {:#?}",
+                    "{} failed to get snapshot of guid {}: {:#?}",
+                    game_name,
                     guid,
                     e
                 );
+                verification.failed += 1;
             }
-        },
-        Err(e) => {
-            log::error!("bf4 failed to get snapshot of guid {}: {:#?}", guid, e);
         }
     }
-
-    Ok((guid, players))
+    verification
 }
 
 async fn get_all_regions(
+    client: &HttpClient,
     game_name: &str,
     base_uri: &str,
-) -> anyhow::Result<HashMap<String, BattlelogServer>> {
+) -> error::Result<(HashMap<String, BattlelogServer>, CrawlReport)> {
     let battlelog_regions = HashMap::from([
         (1, "NAm"),
         (2, "SAm"),
@@ -161,103 +233,130 @@ async fn get_all_regions(
         ("xp4_snowcrash", "Thin ice"),
     ]);
 
-    let mut _offset = 0;
+    let mut offset = 0;
     let per_page = 60;
-    let mut pages_since_last_unique_server = 0;
-    let mut attempt = 0;
-    let max_attempts = 3;
-    let page_limit = 10;
-    let client = reqwest::Client::new();
-    let mut _server_total_before: usize = 0;
+    let max_pages = env::var("BATTLELOG_MAX_PAGES")
+        .ok()
+        .and_then(|pages| pages.parse::<i64>().ok())
+        .unwrap_or(200);
+    // stop when battlelog keeps returning servers we already have
+    let stale_page_limit = 3;
+    let mut stale_pages = 0;
+    let policy = RetryPolicy::from_env();
 
     let mut found_servers: HashMap<String, BattlelogServer> = HashMap::new();
+    let mut report = CrawlReport {
+        pages: 0,
+        rows: 0,
+        servers: 0,
+        duplicates: 0,
+        exhausted: false,
+        keeper: None,
+    };
 
-    while pages_since_last_unique_server < page_limit && attempt < max_attempts {
-        let mut headers = HeaderMap::new();
-        headers.insert("X-Requested-With", "XMLHttpRequest".parse()?);
-        let url = format!("{}?count={}&offset=0", base_uri, per_page);
-        match client.get(url).headers(headers).send().await {
-            Ok(resp) => {
-                match resp.json::<serde_json::Value>().await {
-                    Ok(json_res) => {
-                        attempt = 0;
-                        _server_total_before = found_servers.len();
+    while report.pages < max_pages {
+        let url = &format!("{}?count={}&offset={}", base_uri, per_page, offset);
+        let json_res = retry(
+            &policy,
+            &format!("{} server list", game_name),
+            || async move {
+                let mut headers = HeaderMap::new();
+                headers.insert(
+                    "X-Requested-With",
+                    HeaderValue::from_static("XMLHttpRequest"),
+                );
+                let _permit = client.permit(url).await?;
+                Ok(client
+                    .get(url)
+                    .headers(headers)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<serde_json::Value>()
+                    .await?)
+            },
+        )
+        .await?;
+        report.pages += 1;
 
-                        for server in json_res["data"].as_array().unwrap_or(&vec![]) {
-                            let current_map = server["map"].as_str().unwrap_or_default();
-                            let found_server = BattlelogServer {
-                                game_id: server["gameId"].as_str().unwrap_or_default().to_string(),
-                                name: server["name"].as_str().unwrap_or_default().to_string(),
-                                guid: server["guid"].as_str().unwrap_or_default().to_string(),
-                                ip: server["ip"].as_str().unwrap_or_default().to_string(),
-                                region: battlelog_regions
-                                    .get(&server["region"].as_i64().unwrap_or_default())
-                                    .unwrap_or(&"")
-                                    .to_string(),
-                                queue_amount: server["slots"]["1"]["current"]
-                                    .as_i64()
-                                    .unwrap_or_default(),
-                                soldier_amount: server["slots"]["2"]["current"]
-                                    .as_i64()
-                                    .unwrap_or_default(),
-                                map: match game_name {
-                                    "bfh" => bfh_maps
-                                        .get(current_map)
-                                        .unwrap_or(&current_map)
-                                        .to_string(),
-                                    "bf3" => bf3_maps
-                                        .get(current_map)
-                                        .unwrap_or(&current_map)
-                                        .to_string(),
-                                    _ => bf4_maps
-                                        .get(current_map)
-                                        .unwrap_or(&current_map)
-                                        .to_string(),
-                                },
-                            };
-                            // against duplicates
-                            if !found_server.ip.is_empty()
-                                && !found_servers.contains_key(&found_server.guid)
-                            {
-                                found_servers.insert(found_server.clone().guid, found_server);
-                            }
-                        }
+        let page = json_res["data"].as_array().cloned().unwrap_or_default();
+        if page.is_empty() {
+            report.exhausted = true;
+            break;
+        }
+        report.rows += page.len() as i64;
+        let server_total_before = found_servers.len();
 
-                        if found_servers.len() == _server_total_before {
-                            pages_since_last_unique_server += 1;
-                        } else {
-                            // Found new unique server, reset
-                            pages_since_last_unique_server = 0;
-                        }
-                        _offset += per_page;
-                    }
-                    Err(_) => {
-                        attempt += 1;
-                    }
-                };
+        for server in &page {
+            let current_map = server["map"].as_str().unwrap_or_default();
+            let found_server = BattlelogServer {
+                game_id: server["gameId"].as_str().unwrap_or_default().to_string(),
+                name: server["name"].as_str().unwrap_or_default().to_string(),
+                guid: server["guid"].as_str().unwrap_or_default().to_string(),
+                ip: server["ip"].as_str().unwrap_or_default().to_string(),
+                region: battlelog_regions
+                    .get(&server["region"].as_i64().unwrap_or_default())
+                    .unwrap_or(&"")
+                    .to_string(),
+                queue_amount: server["slots"]["1"]["current"].as_i64().unwrap_or_default(),
+                soldier_amount: server["slots"]["2"]["current"].as_i64().unwrap_or_default(),
+                max_soldiers: server["slots"]["2"]["max"].as_i64().unwrap_or_default(),
+                map: match game_name {
+                    "bfh" => bfh_maps
+                        .get(current_map)
+                        .unwrap_or(&current_map)
+                        .to_string(),
+                    "bf3" => bf3_maps
+                        .get(current_map)
+                        .unwrap_or(&current_map)
+                        .to_string(),
+                    _ => bf4_maps
+                        .get(current_map)
+                        .unwrap_or(&current_map)
+                        .to_string(),
+                },
+            };
+            if found_server.ip.is_empty() {
+                continue;
             }
-            Err(_) => {
-                attempt += 1;
+            // against duplicates
+            match found_servers.contains_key(&found_server.guid) {
+                true => report.duplicates += 1,
+                false => {
+                    found_servers.insert(found_server.clone().guid, found_server);
+                }
             }
-        };
-    }
+        }
 
-    if game_name == "bf4" {
-        let found_server_copy = found_servers.clone();
-        let populated_servers = found_server_copy.values().filter(|&x| x.soldier_amount > 0);
-        let mut tasks = vec![];
-        for server in populated_servers {
-            tasks.push(get_battlelog_keeper_data(&server.guid));
+        if page.len() < per_page {
+            report.exhausted = true;
+            break;
         }
-        let result = join_all(tasks).await;
-        for (server, result) in result.into_iter().flatten() {
-            if let Some(found_server) = found_servers.get_mut(server) {
-                found_server.soldier_amount = result as i64;
+        if found_servers.len() == server_total_before {
+            stale_pages += 1;
+            if stale_pages >= stale_page_limit {
+                log::warn!(
+                    "{} server list returned no new servers for {} pages at offset {}, stopping",
+                    game_name,
+                    stale_pages,
+                    offset
+                );
+                break;
             }
+        } else {
+            stale_pages = 0;
         }
+        offset += per_page;
+    }
+    report.servers = found_servers.len() as i64;
+
+    // games with keeper snapshots, comma separated
+    let keeper_games = env::var("BATTLELOG_KEEPER_GAMES").unwrap_or("bf4".to_string());
+    if keeper_games.split(',').any(|game| game.trim() == game_name) {
+        report.keeper = Some(verify_with_keeper(client, game_name, &mut found_servers).await);
     }
 
-    Ok(found_servers)
+    Ok((found_servers, report))
 }
 
 async fn server_list_to_sum(
@@ -283,38 +382,39 @@ async fn server_list_to_sum(
             community_soldier_amount: 0,
             community_queue_amount: 0,
             community_spectator_amount: 0,
+            slot_amount: 0,
+            slotted_soldier_amount: 0,
         },
-        maps: HashMap::new(),
-        modes: HashMap::new(),
-        settings: HashMap::new(),
-        owner_platform: HashMap::new(),
+        breakdown: results::Breakdown::default(),
         timestamp: Utc::now(),
-        map_players: HashMap::new(),
-        mode_players: HashMap::new(),
-        settings_players: HashMap::new(),
-        playground: HashMap::new(),
-        playground_players: HashMap::new(),
     };
     let mut server_stats: HashMap<String, Vec<server_info::ServerInfo>> = HashMap::new();
 
     let mut regions: HashMap<String, results::RegionResult> = HashMap::new();
     for server in found_servers.values() {
+        let max_players = server_info::capacity(server.max_soldiers);
+        let country = geoip::lookup(&server.ip)
+            .map(|location| location.country)
+            .unwrap_or_default();
         regions
             .entry(server.region.to_string())
             .and_modify(|region| {
                 region.amounts.server_amount += 1;
                 region.amounts.soldier_amount += server.soldier_amount;
                 region.amounts.queue_amount += server.queue_amount;
-                region
-                    .maps
-                    .entry(server.map.clone())
-                    .and_modify(|count| *count += 1)
-                    .or_insert(1);
-                region
-                    .map_players
-                    .entry(server.map.clone())
-                    .and_modify(|count| *count += server.soldier_amount)
-                    .or_insert(server.soldier_amount);
+                region.amounts.add_slots(server.soldier_amount, max_players);
+                region.breakdown.add(
+                    results::Dimension::Map,
+                    &server.map,
+                    server.soldier_amount,
+                    max_players,
+                );
+                region.breakdown.add(
+                    results::Dimension::Country,
+                    &country,
+                    server.soldier_amount,
+                    max_players,
+                );
             })
             .or_insert({
                 results::RegionResult {
@@ -335,17 +435,18 @@ async fn server_list_to_sum(
                         community_soldier_amount: 0,
                         community_queue_amount: 0,
                         community_spectator_amount: 0,
+                        slot_amount: max_players.unwrap_or_default(),
+                        slotted_soldier_amount: max_players.map_or(0, |_| server.soldier_amount),
                     },
-                    maps: HashMap::from([(server.map.clone(), 1)]),
-                    modes: HashMap::new(),
-                    settings: HashMap::new(),
-                    owner_platform: HashMap::new(),
+                    breakdown: results::Breakdown::server(
+                        [
+                            (results::Dimension::Map, &server.map[..]),
+                            (results::Dimension::Country, &country[..]),
+                        ],
+                        server.soldier_amount,
+                        max_players,
+                    ),
                     timestamp: Utc::now(),
-                    map_players: HashMap::from([(server.map.clone(), server.soldier_amount)]),
-                    mode_players: HashMap::new(),
-                    settings_players: HashMap::new(),
-                    playground: HashMap::new(),
-                    playground_players: HashMap::new(),
                 }
             });
 
@@ -354,20 +455,25 @@ async fn server_list_to_sum(
             .and_modify(|region_info| region_info.push(server.clone().into()))
             .or_insert_with(|| vec![std::convert::Into::<ServerInfo>::into(server.clone())]);
 
-        all_regions
-            .maps
-            .entry(server.map.clone())
-            .and_modify(|count| *count += 1)
-            .or_insert(1);
-        all_regions
-            .map_players
-            .entry(server.map.clone())
-            .and_modify(|count| *count += server.soldier_amount)
-            .or_insert(server.soldier_amount);
+        all_regions.breakdown.add(
+            results::Dimension::Map,
+            &server.map,
+            server.soldier_amount,
+            max_players,
+        );
+        all_regions.breakdown.add(
+            results::Dimension::Country,
+            &country,
+            server.soldier_amount,
+            max_players,
+        );
 
         all_regions.amounts.server_amount += 1;
         all_regions.amounts.soldier_amount += server.soldier_amount;
         all_regions.amounts.queue_amount += server.queue_amount;
+        all_regions
+            .amounts
+            .add_slots(server.soldier_amount, max_players);
     }
     regions.insert("ALL".to_string(), all_regions);
 
@@ -387,36 +493,84 @@ async fn server_list_to_sum(
 }
 
 async fn get_region_stats(
+    client: &HttpClient,
     pool: &PgPool,
+    influx_client: &influxdb2::Client,
     game_name: &str,
     base_uri: &str,
-) -> anyhow::Result<HashMap<String, results::RegionResult>> {
-    let found_servers = get_all_regions(game_name, base_uri).await?;
+) -> error::Result<(HashMap<String, results::RegionResult>, CrawlReport)> {
+    let (found_servers, report) = get_all_regions(client, game_name, base_uri).await?;
+    let found_servers = validation::validate(
+        influx_client,
+        game_name,
+        "pc",
+        found_servers.into_values().collect(),
+    )
+    .await
+    .into_iter()
+    .map(|server| (server.guid.clone(), server))
+    .collect();
     let result = server_list_to_sum(pool, game_name, found_servers).await?;
 
-    Ok(result)
+    Ok((result, report))
 }
 
 pub async fn gather_battlelog(
+    client: &HttpClient,
     pool: &PgPool,
     influx_client: &influxdb2::Client,
+    anomalies: &Anomalies,
     game_name: &str,
     base_uri: &str,
-) -> anyhow::Result<results::RegionResult> {
-    let game_result = match get_region_stats(pool, game_name, base_uri).await {
-        Ok(result) => {
+) -> error::Result<results::RegionResult> {
+    let game_result = match get_region_stats(client, pool, influx_client, game_name, base_uri).await
+    {
+        Ok((result, report)) => {
             // influx
-            match influx_db::push_to_database(influx_client, game_name, "pc", &result).await {
+            match anomalies
+                .publish(influx_client, game_name, "pc", &result)
+                .await
+            {
                 Ok(_) => {}
                 Err(e) => log::error!("{} failed to push to influxdb: {:#?}", game_name, e),
             };
+            if !report.exhausted {
+                log::warn!(
+                    "{} crawl stopped before the end of the server list after {} pages",
+                    game_name,
+                    report.pages
+                );
+            }
+            if let Some(verification) = &report.keeper {
+                if verification.failed > 0 {
+                    log::warn!(
+                        "{} soldier amount is partially verified, {}/{} keeper snapshots failed",
+                        game_name,
+                        verification.failed,
+                        verification.checked
+                    );
+                }
+            }
+            match influx_db::push_crawl_report(influx_client, game_name, &report).await {
+                Ok(_) => {}
+                Err(e) => log::error!(
+                    "{} failed to push crawl report to influxdb: {:#?}",
+                    game_name,
+                    e
+                ),
+            };
             result
         }
-        Err(e) => anyhow::bail!("{} gather failed: {:#?}", game_name, e),
+        Err(e) => return Err(e),
     };
     let result = match game_result.get("ALL") {
         Some(result) => result,
-        None => anyhow::bail!("{} has no ALL region!", game_name),
+        None => {
+            return Err(GatherError::UpstreamEmpty(format!(
+                "{} has no ALL region!",
+                game_name
+            )))
+        }
     };
 
     Ok(result.to_owned())
@@ -0,0 +1,194 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "state", rename_all = "camelCase")]
+pub enum BreakerState {
+    Closed {
+        #[serde(rename = "consecutiveFailures")]
+        consecutive_failures: u32,
+    },
+    Open {
+        until: DateTime<Utc>,
+    },
+    HalfOpen,
+}
+
+// one breaker per source (game key, with a suffix when a game has more sources), shared with
+// the status endpoint
+#[derive(Debug, Clone)]
+pub struct CircuitBreakers {
+    failure_threshold: u32,
+    cool_down: chrono::Duration,
+    states: Arc<Mutex<HashMap<String, BreakerState>>>,
+}
+
+impl CircuitBreakers {
+    pub fn from_env() -> Self {
+        CircuitBreakers {
+            failure_threshold: env::var("CIRCUIT_BREAKER_FAILURES")
+                .ok()
+                .and_then(|failures| failures.parse::<u32>().ok())
+                .unwrap_or(3)
+                .max(1),
+            cool_down: chrono::Duration::minutes(
+                env::var("CIRCUIT_BREAKER_COOLDOWN_MINUTES")
+                    .ok()
+                    .and_then(|mins| mins.parse::<i64>().ok())
+                    .unwrap_or(15),
+            ),
+            states: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // false while the source is cooling down, lets a single probe through afterwards
+    pub fn allow(&self, source: &str) -> bool {
+        let mut states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        match states.get(source) {
+            Some(BreakerState::Open { until }) => {
+                if Utc::now() < *until {
+                    return false;
+                }
+                log::info!("{} circuit half-open, probing", source);
+                states.insert(source.to_string(), BreakerState::HalfOpen);
+                true
+            }
+            _ => true,
+        }
+    }
+
+    pub fn record_success(&self, source: &str) {
+        let mut states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(BreakerState::HalfOpen) = states.get(source) {
+            log::info!("{} circuit closed again", source);
+        }
+        states.insert(
+            source.to_string(),
+            BreakerState::Closed {
+                consecutive_failures: 0,
+            },
+        );
+    }
+
+    pub fn record_failure(&self, source: &str) {
+        let mut states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        let consecutive_failures = match states.get(source) {
+            Some(BreakerState::Closed {
+                consecutive_failures,
+            }) => consecutive_failures + 1,
+            None => 1,
+            // a failed probe opens the circuit again right away
+            Some(_) => self.failure_threshold,
+        };
+        let state = if consecutive_failures >= self.failure_threshold {
+            let until = Utc::now() + self.cool_down;
+            log::warn!("{} circuit open until {}", source, until);
+            BreakerState::Open { until }
+        } else {
+            BreakerState::Closed {
+                consecutive_failures,
+            }
+        };
+        states.insert(source.to_string(), state);
+    }
+
+    pub fn states(&self) -> HashMap<String, BreakerState> {
+        self.states
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breakers(cool_down: chrono::Duration) -> CircuitBreakers {
+        CircuitBreakers {
+            failure_threshold: 3,
+            cool_down,
+            states: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn state(breakers: &CircuitBreakers, source: &str) -> Option<BreakerState> {
+        breakers.states().remove(source)
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breakers = breakers(chrono::Duration::minutes(15));
+        assert!(breakers.allow("bf4"));
+        breakers.record_failure("bf4");
+        breakers.record_failure("bf4");
+        assert!(matches!(
+            state(&breakers, "bf4"),
+            Some(BreakerState::Closed {
+                consecutive_failures: 2
+            })
+        ));
+        assert!(breakers.allow("bf4"));
+
+        breakers.record_failure("bf4");
+        assert!(matches!(
+            state(&breakers, "bf4"),
+            Some(BreakerState::Open { .. })
+        ));
+        assert!(!breakers.allow("bf4"));
+        // other sources keep their own breaker
+        assert!(breakers.allow("bf1"));
+    }
+
+    #[test]
+    fn success_resets_the_failures() {
+        let breakers = breakers(chrono::Duration::minutes(15));
+        breakers.record_failure("bf4");
+        breakers.record_failure("bf4");
+        breakers.record_success("bf4");
+        breakers.record_failure("bf4");
+        assert!(matches!(
+            state(&breakers, "bf4"),
+            Some(BreakerState::Closed {
+                consecutive_failures: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn half_open_probe_closes_or_opens_again() {
+        // the cool down is over right away
+        let breakers = breakers(chrono::Duration::zero());
+        for _ in 0..3 {
+            breakers.record_failure("bf4");
+        }
+        assert!(breakers.allow("bf4"));
+        assert!(matches!(
+            state(&breakers, "bf4"),
+            Some(BreakerState::HalfOpen)
+        ));
+        breakers.record_success("bf4");
+        assert!(matches!(
+            state(&breakers, "bf4"),
+            Some(BreakerState::Closed {
+                consecutive_failures: 0
+            })
+        ));
+
+        for _ in 0..3 {
+            breakers.record_failure("bf4");
+        }
+        assert!(breakers.allow("bf4"));
+        // a single failed probe is enough
+        breakers.record_failure("bf4");
+        assert!(matches!(
+            state(&breakers, "bf4"),
+            Some(BreakerState::Open { .. })
+        ));
+    }
+}
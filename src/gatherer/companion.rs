@@ -1,5 +1,7 @@
 use crate::{
-    connectors::{influx_db, timescale_db::push_server},
+    connectors::timescale_db::push_server,
+    error::{self, GatherError},
+    gatherer::{anomaly::Anomalies, validation},
     structs::{
         companion::{Regions, ServerFilter, Slots, UnusedValue},
         results, server_info,
@@ -114,7 +116,7 @@ async fn region_players(
     game_name: String,
     frontend_game_name: String,
     platform: String,
-) -> anyhow::Result<results::RegionResult> {
+) -> anyhow::Result<(results::RegionResult, HashMap<&'static str, i64>)> {
     let game_maps = filter_maps(&game_name).await;
     let default = &vec![];
 
@@ -133,11 +135,11 @@ async fn region_players(
         community_soldier_amount: 0,
         community_queue_amount: 0,
         community_spectator_amount: 0,
+        slot_amount: 0,
+        slotted_soldier_amount: 0,
     };
-    let mut map_amounts: HashMap<String, i64> = HashMap::new();
-    let mut mode_amounts: HashMap<String, i64> = HashMap::new();
-    let mut map_player_amounts: HashMap<String, i64> = HashMap::new();
-    let mut mode_player_amounts: HashMap<String, i64> = HashMap::new();
+    let mut breakdown = results::Breakdown::default();
+    let mut validation = validation::Validation::new(validation::Validator::from_env());
 
     for current_map in game_maps.clone().keys() {
         let off = "off";
@@ -202,12 +204,9 @@ async fn region_players(
         let servers = result["result"]["gameservers"]
             .as_array()
             .unwrap_or(default);
-        region_amounts.server_amount += servers.len() as i64;
 
         for server in servers {
             let slots = &server["slots"];
-            let server_soldier_amount = slots["Soldier"]["current"].as_i64().unwrap_or_default();
-            let server_queue_amount = slots["Queue"]["current"].as_i64().unwrap_or_default();
             let server_spectator_amount =
                 slots["Spectator"]["current"].as_i64().unwrap_or_default();
 
@@ -218,38 +217,62 @@ async fn region_players(
 
             let mode_name = server["mapMode"].as_str().unwrap_or_default().to_string();
 
-            mode_amounts
-                .entry(mode_name.clone())
-                .and_modify(|count| *count += 1)
-                .or_insert(1);
-
-            mode_player_amounts
-                .entry(mode_name.clone())
-                .and_modify(|count| *count += server_soldier_amount)
-                .or_insert(server_soldier_amount);
+            // dice server
+            let is_official = (server["serverType"].as_str().unwrap_or_default() == "OFFICIAL"
+                && server["game"].as_str().unwrap_or_default() == "tunguska")
+                || (server["ownerId"].as_str().is_none()
+                    && server["game"].as_str().unwrap_or_default() == "casablanca");
 
-            map_amounts
-                .entry(map_name.clone())
-                .and_modify(|count| *count += 1)
-                .or_insert(1);
+            let mut server_info = server_info::ServerInfo {
+                game_id: server["gameId"].as_str().unwrap_or_default().to_owned(),
+                guid: server["guid"].as_str().unwrap_or_default().to_owned(),
+                name: server["name"].as_str().unwrap_or_default().to_owned(),
+                soldiers: slots["Soldier"]["current"].as_i64().unwrap_or_default(),
+                queue: slots["Queue"]["current"].as_i64().unwrap_or_default(),
+                mode: mode_name,
+                map: map_name,
+                is_official: Some(is_official),
+                max_players: server_info::capacity(
+                    slots["Soldier"]["max"].as_i64().unwrap_or_default(),
+                ),
+                settings: vec![],
+            };
+            if !validation.check(&mut server_info) {
+                continue;
+            }
+            let server_soldier_amount = server_info.soldiers;
+            let server_queue_amount = server_info.queue;
+            let max_players = server_info.max_players;
 
-            map_player_amounts
-                .entry(map_name.clone())
-                .and_modify(|count| *count += server_soldier_amount)
-                .or_insert(server_soldier_amount);
+            breakdown.add(
+                results::Dimension::Mode,
+                &server_info.mode,
+                server_soldier_amount,
+                max_players,
+            );
+            breakdown.add(
+                results::Dimension::Map,
+                &server_info.map,
+                server_soldier_amount,
+                max_players,
+            );
+            breakdown.add(
+                results::Dimension::Country,
+                &server["country"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_uppercase(),
+                server_soldier_amount,
+                max_players,
+            );
 
+            region_amounts.server_amount += 1;
             region_amounts.soldier_amount += server_soldier_amount;
             region_amounts.queue_amount += server_queue_amount;
             region_amounts.spectator_amount += server_spectator_amount;
+            region_amounts.add_slots(server_soldier_amount, max_players);
 
-            let mut is_official = false;
-            // add dice server count
-            if (server["serverType"].as_str().unwrap_or_default() == "OFFICIAL"
-                && server["game"].as_str().unwrap_or_default() == "tunguska")
-                || (server["ownerId"].as_str().is_none()
-                    && server["game"].as_str().unwrap_or_default() == "casablanca")
-            {
-                is_official = true;
+            if is_official {
                 region_amounts.dice_server_amount += 1;
                 region_amounts.dice_soldier_amount += server_soldier_amount;
                 region_amounts.dice_queue_amount += server_queue_amount;
@@ -262,16 +285,7 @@ async fn region_players(
                 region_amounts.community_spectator_amount += server_spectator_amount;
             }
 
-            server_stats.push(server_info::ServerInfo {
-                game_id: server["gameId"].as_str().unwrap_or_default().to_owned(),
-                guid: server["guid"].as_str().unwrap_or_default().to_owned(),
-                name: server["name"].as_str().unwrap_or_default().to_owned(),
-                soldiers: server_soldier_amount,
-                queue: server_queue_amount,
-                mode: mode_name,
-                map: map_name,
-                is_official: Some(is_official),
-            });
+            server_stats.push(server_info);
         }
     }
 
@@ -291,19 +305,11 @@ async fn region_players(
             platform: platform.to_string(),
         },
         amounts: region_amounts,
-        maps: map_amounts,
-        modes: mode_amounts,
-        settings: HashMap::new(),
-        owner_platform: HashMap::new(),
+        breakdown,
         timestamp: Utc::now(),
-        map_players: map_player_amounts,
-        mode_players: mode_player_amounts,
-        settings_players: HashMap::new(),
-        playground: HashMap::new(),
-        playground_players: HashMap::new(),
     };
 
-    Ok(region_result)
+    Ok((region_result, validation.rejections))
 }
 
 async fn get_region_stats(
@@ -312,17 +318,26 @@ async fn get_region_stats(
     old_session: String,
     cookie: bf_sparta::cookie::Cookie,
     platform: &str,
-) -> anyhow::Result<(String, HashMap<String, results::RegionResult>)> {
+) -> anyhow::Result<(
+    String,
+    HashMap<String, results::RegionResult>,
+    HashMap<&'static str, i64>,
+)> {
     let (game_name, frontend_game_name) = game_names;
     let session =
         match sparta_api::check_gateway_session(cookie, &old_session, platform, game_name, "en-us")
             .await
         {
             Ok(session) => session,
-            Err(e) => anyhow::bail!("{} session failed: {:#?}", game_name, e),
+            Err(e) => {
+                return Err(
+                    GatherError::Auth(format!("{} session failed: {:#?}", game_name, e)).into(),
+                )
+            }
         };
     let sparta_regions = vec!["EU", "Asia", "NAm", "SAm", "AU", "OC", "Afr", "AC"];
     let mut platform_result: HashMap<String, results::RegionResult> = HashMap::new();
+    let mut rejections: HashMap<&'static str, i64> = HashMap::new();
 
     let mut set = JoinSet::new();
     for region in sparta_regions {
@@ -339,8 +354,11 @@ async fn get_region_stats(
     while let Some(res) = set.join_next().await {
         let out = res?;
         match out {
-            Ok(region_result) => {
+            Ok((region_result, region_rejections)) => {
                 platform_result.insert(region_result.clone().metadata.region, region_result);
+                for (rule, amount) in region_rejections {
+                    *rejections.entry(rule).or_insert(0) += amount;
+                }
             }
             Err(e) => {
                 log::error!("{} region failed: {:#?}", game_name, e);
@@ -350,17 +368,18 @@ async fn get_region_stats(
 
     let all_regions = results::combine_region_players("ALL", platform, &platform_result).await;
     platform_result.insert("ALL".to_string(), all_regions);
-    Ok((session.session_id, platform_result))
+    Ok((session.session_id, platform_result, rejections))
 }
 
 pub async fn gather_companion(
     pool: &PgPool,
     influx_client: &influxdb2::Client,
+    anomalies: &Anomalies,
     mut sessions: HashMap<String, String>,
     cookie: bf_sparta::cookie::Cookie,
     game_name: &str,
     frontend_game_name: &str,
-) -> anyhow::Result<(HashMap<String, String>, results::RegionResult)> {
+) -> error::Result<(HashMap<String, String>, results::RegionResult)> {
     let game_platforms = match &game_name.to_string()[..] {
         "tunguska" => vec!["pc", "ps4", "xboxone"],
         "casablanca" => vec!["pc", "ps4", "xboxone"],
@@ -382,22 +401,27 @@ pub async fn gather_companion(
         )
         .await
         {
-            Ok((sessions, platform_result)) => {
+            Ok((sessions, platform_result, rejections)) => {
+                validation::report(influx_client, frontend_game_name, platform, &rejections).await;
                 // influx
-                match influx_db::push_to_database(
-                    influx_client,
-                    frontend_game_name,
-                    platform,
-                    &platform_result,
-                )
-                .await
+                match anomalies
+                    .publish(
+                        influx_client,
+                        frontend_game_name,
+                        platform,
+                        &platform_result,
+                    )
+                    .await
                 {
                     Ok(_) => {}
                     Err(e) => log::error!("{} failed to push to influxdb: {:#?}", game_name, e),
                 };
                 (sessions, platform_result)
             }
-            Err(e) => anyhow::bail!("{} failed with platform {}: {:#?}", platform, game_name, e),
+            Err(e) => {
+                log::error!("{} failed with platform {}", game_name, platform);
+                return Err(e.into());
+            }
         };
         sessions.insert(platform.into(), session);
         game_result.insert(platform.into(), platform_result);
@@ -405,13 +429,14 @@ pub async fn gather_companion(
 
     let combined_platform_regions = results::combine_region_platforms(&game_result).await;
     // influx
-    match influx_db::push_to_database(
-        influx_client,
-        frontend_game_name,
-        "global",
-        &combined_platform_regions,
-    )
-    .await
+    match anomalies
+        .publish(
+            influx_client,
+            frontend_game_name,
+            "global",
+            &combined_platform_regions,
+        )
+        .await
     {
         Ok(_) => {}
         Err(e) => log::error!("{} failed to push to influxdb: {:#?}", game_name, e),
@@ -419,7 +444,12 @@ pub async fn gather_companion(
 
     let result = match combined_platform_regions.get("ALL") {
         Some(result) => result,
-        None => anyhow::bail!("{} has no ALL region!", game_name),
+        None => {
+            return Err(GatherError::UpstreamEmpty(format!(
+                "{} has no ALL region!",
+                game_name
+            )))
+        }
     };
 
     Ok((sessions, result.to_owned()))
@@ -0,0 +1,128 @@
+use futures::{stream, StreamExt};
+use influxdb2::models::DataPoint;
+use std::{collections::HashMap, env};
+
+use super::http::HttpClient;
+use crate::{
+    connectors::server_registry,
+    error,
+    structs::game_players::{PlayerList, RegionPlayerSummary},
+};
+
+async fn gather_players(client: &HttpClient, url: &str) -> error::Result<PlayerList> {
+    let _permit = client.permit(url).await?;
+    Ok(client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<PlayerList>()
+        .await?)
+}
+
+// fetches the player lists of the populated servers of the last gather and stores the per
+// region summaries, the lists themselves (names, ids) are never stored.
+// the lists come from the gametools api by gameId, GAME_PLAYERS_URL overrides the url template.
+// GAME_PLAYERS_CONCURRENCY limits the requests at once (10).
+// returns the amount of servers that had a player list
+pub async fn push_game_players(
+    client: &HttpClient,
+    influx_client: &influxdb2::Client,
+    frontend_game_name: &str,
+) -> error::Result<usize> {
+    let url_template = env::var("GAME_PLAYERS_URL")
+        .unwrap_or("https://api.gametools.network/{game}/players/?gameid={game_id}".to_string());
+    let concurrency = env::var("GAME_PLAYERS_CONCURRENCY")
+        .ok()
+        .and_then(|concurrency| concurrency.parse::<usize>().ok())
+        .unwrap_or(10)
+        .max(1);
+
+    let servers = server_registry::populated(frontend_game_name).await;
+    let results: Vec<(String, error::Result<PlayerList>)> = stream::iter(servers)
+        .map(|(region, server)| {
+            let url = url_template
+                .replace("{game}", frontend_game_name)
+                .replace("{game_id}", &server.game_id);
+            async move { (region, gather_players(client, &url).await) }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let mut summaries: HashMap<String, RegionPlayerSummary> = HashMap::new();
+    let mut failed = 0;
+    for (region, result) in results {
+        match result {
+            Ok(player_list) => {
+                summaries.entry(region).or_default().add(&player_list);
+            }
+            Err(_) => failed += 1,
+        }
+    }
+    if failed > 0 {
+        log::warn!(
+            "{} failed to get {} player lists",
+            frontend_game_name,
+            failed
+        );
+    }
+
+    let mut all_regions = RegionPlayerSummary::default();
+    for summary in summaries.values() {
+        all_regions.merge(summary);
+    }
+    summaries.insert("ALL".to_string(), all_regions);
+    let servers = summaries["ALL"].servers as usize;
+
+    push_summaries(influx_client, frontend_game_name, &summaries).await?;
+    Ok(servers)
+}
+
+async fn push_summaries(
+    influx_client: &influxdb2::Client,
+    frontend_game_name: &str,
+    summaries: &HashMap<String, RegionPlayerSummary>,
+) -> error::Result<()> {
+    let mut points = vec![];
+    for (region, summary) in summaries {
+        points.push(
+            DataPoint::builder(frontend_game_name)
+                .tag("region", region)
+                .tag("type", "playerSummary")
+                .field("servers", summary.servers)
+                .field("players", summary.players)
+                .field("averageRank", summary.average_rank())
+                .field("averageTeamDifference", summary.average_team_difference())
+                .build()?,
+        );
+        for (bucket, players) in &summary.latency {
+            points.push(
+                DataPoint::builder(frontend_game_name)
+                    .tag("region", region)
+                    .tag("type", "playerLatency")
+                    .tag("latency", *bucket)
+                    .field("players", *players)
+                    .build()?,
+            );
+        }
+        for (platform, players) in &summary.platforms {
+            points.push(
+                DataPoint::builder(frontend_game_name)
+                    .tag("region", region)
+                    .tag("type", "playerPlatform")
+                    .tag("playerPlatform", platform)
+                    .field("players", *players)
+                    .build()?,
+            );
+        }
+    }
+    influx_client
+        .write_with_precision(
+            "Game info",
+            stream::iter(points),
+            influxdb2::api::write::TimestampPrecision::Seconds,
+        )
+        .await?;
+    Ok(())
+}
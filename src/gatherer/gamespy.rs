@@ -0,0 +1,1017 @@
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use std::{
+    collections::HashMap,
+    env,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::{
+    error::{self, GatherError},
+    structs::old_games::{OldGameServer, OldGameServerList},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryProtocol {
+    // "\info\" text query, bf1942 and bfvietnam
+    V1,
+    // binary query, bf2
+    V3,
+    // v3 with a challenge first, bf2142
+    V4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListProtocol {
+    // "\list\" of the legacy master (enctype 0, usually on port 28900), bf1942 and bfvietnam
+    Legacy,
+    // server browsing list (enctypex, usually on port 28910), bf2 and bf2142
+    ServerBrowsing,
+}
+
+#[derive(Debug, Clone)]
+pub struct MasterConfig {
+    pub address: String,
+    pub game_name: String,
+    pub game_key: String,
+    pub list_protocol: ListProtocol,
+    pub query_protocol: QueryProtocol,
+    // per server query, the master list gets 10 times as long
+    pub timeout: Duration,
+    pub concurrency: usize,
+}
+
+impl MasterConfig {
+    // GAMESPY_MASTER_<SOURCE>=host:port enables the native list for a source, like
+    // GAMESPY_MASTER_BF2_BF2HUB for "bf2-bf2hub". GAMESPY_KEY_<SOURCE> overrides the game key
+    pub fn from_env(mongo_game_name: &str) -> Option<Self> {
+        let source = mongo_game_name.to_uppercase().replace('-', "_");
+        let address = env::var(format!("GAMESPY_MASTER_{}", source)).ok()?;
+        let (game_name, game_key, list_protocol, query_protocol) =
+            match mongo_game_name.split('-').next()? {
+                "bf2" => (
+                    "battlefield2",
+                    "hW6m9a",
+                    ListProtocol::ServerBrowsing,
+                    QueryProtocol::V3,
+                ),
+                "bf2142" => (
+                    "stella",
+                    "M8o1Qw",
+                    ListProtocol::ServerBrowsing,
+                    QueryProtocol::V4,
+                ),
+                "bfield1942" => (
+                    "bfield1942",
+                    "HpWx9z",
+                    ListProtocol::Legacy,
+                    QueryProtocol::V1,
+                ),
+                "bfvietnam" => (
+                    "bfvietnam",
+                    "h2P9dJ",
+                    ListProtocol::Legacy,
+                    QueryProtocol::V1,
+                ),
+                _ => return None,
+            };
+        Some(MasterConfig {
+            address,
+            game_name: game_name.to_string(),
+            game_key: env::var(format!("GAMESPY_KEY_{}", source)).unwrap_or(game_key.to_string()),
+            list_protocol,
+            query_protocol,
+            timeout: Duration::from_millis(
+                env::var("GAMESPY_TIMEOUT_MS")
+                    .ok()
+                    .and_then(|ms| ms.parse::<u64>().ok())
+                    .unwrap_or(2000),
+            ),
+            concurrency: env::var("GAMESPY_CONCURRENCY")
+                .ok()
+                .and_then(|concurrency| concurrency.parse::<usize>().ok())
+                .unwrap_or(50)
+                .max(1),
+        })
+    }
+}
+
+// upper bound of the packets of one split response
+const MAX_PACKETS: usize = 16;
+
+// answer to the "\secure\" challenge of the master server (gsmsalg, enctype 0)
+pub fn validate(secure: &[u8], game_key: &[u8]) -> String {
+    if game_key.is_empty() {
+        return String::new();
+    }
+    let mut table: Vec<u8> = (0..=255).collect();
+    let mut a: u8 = 0;
+    for i in 0..256 {
+        a = a
+            .wrapping_add(table[i])
+            .wrapping_add(game_key[i % game_key.len()]);
+        table.swap(a as usize, i);
+    }
+
+    let mut a: u8 = 0;
+    let mut b: u8 = 0;
+    let mut encrypted = vec![];
+    for &byte in secure {
+        a = a.wrapping_add(byte).wrapping_add(1);
+        let x = table[a as usize];
+        b = b.wrapping_add(x);
+        let y = table[b as usize];
+        table[b as usize] = x;
+        table[a as usize] = y;
+        encrypted.push(byte ^ table[x.wrapping_add(y) as usize]);
+    }
+    while encrypted.len() % 3 != 0 {
+        encrypted.push(0);
+    }
+
+    let alphabet = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::new();
+    for chunk in encrypted.chunks(3) {
+        let (x, y, z) = (chunk[0], chunk[1], chunk[2]);
+        for index in [
+            x >> 2,
+            ((x & 3) << 4) | (y >> 4),
+            ((y & 15) << 2) | (z >> 6),
+            z & 63,
+        ] {
+            result.push(alphabet[index as usize] as char);
+        }
+    }
+    result
+}
+
+// "\key\value\key\value" into pairs, keys are lowercased
+fn parse_backslash_pairs(text: &str) -> HashMap<String, String> {
+    let mut parts = text.trim_start_matches('\\').split('\\');
+    let mut pairs = HashMap::new();
+    while let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+        if key.is_empty() || key == "final" {
+            break;
+        }
+        pairs.insert(key.to_lowercase(), value.to_string());
+    }
+    pairs
+}
+
+async fn read_until(
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    end: &[u8],
+) -> anyhow::Result<()> {
+    let mut chunk = [0u8; 4096];
+    while !buffer.windows(end.len()).any(|window| window == end) {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+    Ok(())
+}
+
+// server list of the legacy master protocol, as "\ip\1.2.3.4:23000" entries
+async fn legacy_list(config: &MasterConfig) -> anyhow::Result<Vec<SocketAddr>> {
+    let list_timeout = config.timeout * 10;
+    let mut stream = timeout(list_timeout, TcpStream::connect(&config.address)).await??;
+
+    let mut buffer = vec![];
+    timeout(
+        list_timeout,
+        read_until(&mut stream, &mut buffer, b"\\secure\\"),
+    )
+    .await??;
+    let greeting = String::from_utf8_lossy(&buffer).to_string();
+    let secure = match parse_backslash_pairs(&greeting).get("secure") {
+        Some(secure) => secure.clone(),
+        None => anyhow::bail!("{} sent no secure challenge", config.address),
+    };
+
+    let request = format!(
+        "\\gamename\\{game}\\enctype\\0\\validate\\{validate}\\final\\\\queryid\\1.1\\\\list\\\\gamename\\{game}\\final\\",
+        game = config.game_name,
+        validate = validate(secure.as_bytes(), config.game_key.as_bytes()),
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buffer = vec![];
+    timeout(
+        list_timeout,
+        read_until(&mut stream, &mut buffer, b"\\final\\"),
+    )
+    .await??;
+    let list = String::from_utf8_lossy(&buffer).to_string();
+    if list.contains("\\error\\") {
+        anyhow::bail!("{} refused the list: {}", config.address, list.trim());
+    }
+
+    let mut parts = list.split('\\');
+    let mut servers = vec![];
+    while let Some(part) = parts.next() {
+        if part != "ip" {
+            continue;
+        }
+        if let Some(Ok(address)) = parts.next().map(|address| address.parse::<SocketAddr>()) {
+            servers.push(address);
+        }
+    }
+    Ok(servers)
+}
+
+// cipher of the server browsing list (aluigi's enctypex). the key comes from the game key,
+// the challenge of the request and the challenge the master sends in front of the list
+struct Enctypex {
+    key: [u8; 261],
+}
+
+impl Enctypex {
+    // the header is "len1 ^ 0xEC", len1 random bytes, "len2 ^ 0xEA" and the len2 bytes of
+    // the master challenge. None until all of it arrived, else the cipher and the list offset
+    fn from_header(game_key: &[u8], challenge: &[u8; 8], header: &[u8]) -> Option<(Self, usize)> {
+        let challenge_start = (*header.first()? ^ 0xEC) as usize + 2;
+        let challenge_length = (*header.get(challenge_start - 1)? ^ 0xEA) as usize;
+        let master_challenge = header.get(challenge_start..challenge_start + challenge_length)?;
+
+        let mut seed = *challenge;
+        for (i, byte) in master_challenge.iter().enumerate() {
+            let value = seed[i & 7] ^ byte;
+            seed[(game_key[i % game_key.len()] as usize * i) & 7] ^= value;
+        }
+        Some((Enctypex::new(&seed), challenge_start + challenge_length))
+    }
+
+    fn new(seed: &[u8; 8]) -> Self {
+        let mut key = [0u8; 261];
+        for (i, value) in key.iter_mut().take(256).enumerate() {
+            *value = i as u8;
+        }
+        let (mut n1, mut n2) = (0usize, 0usize);
+        for i in (0..256).rev() {
+            let j = Enctypex::shuffle_index(&key, i, seed, &mut n1, &mut n2);
+            key.swap(i, j);
+        }
+        key[256] = key[1];
+        key[257] = key[3];
+        key[258] = key[5];
+        key[259] = key[7];
+        key[260] = key[n1 & 0xFF];
+        Enctypex { key }
+    }
+
+    fn shuffle_index(
+        key: &[u8; 261],
+        limit: usize,
+        seed: &[u8; 8],
+        n1: &mut usize,
+        n2: &mut usize,
+    ) -> usize {
+        if limit == 0 {
+            return 0;
+        }
+        let mut mask = 1;
+        while mask < limit {
+            mask = (mask << 1) + 1;
+        }
+        let mut tries = 0;
+        loop {
+            *n1 = key[*n1 & 0xFF] as usize + seed[*n2] as usize;
+            *n2 += 1;
+            if *n2 >= seed.len() {
+                *n2 = 0;
+                *n1 += seed.len();
+            }
+            let mut index = *n1 & mask;
+            tries += 1;
+            if tries > 11 {
+                index %= limit;
+            }
+            if index <= limit {
+                return index;
+            }
+        }
+    }
+
+    // next byte of the key stream, step for step the reference implementation
+    fn stream_byte(&mut self) -> u8 {
+        let k = &mut self.key;
+        let mut a = k[256];
+        let mut b = k[257];
+        let mut c = k[a as usize];
+        k[256] = a.wrapping_add(1);
+        k[257] = b.wrapping_add(c);
+        a = k[260];
+        b = k[k[257] as usize];
+        c = k[a as usize];
+        k[a as usize] = b;
+        a = k[k[259] as usize];
+        b = k[257];
+        k[b as usize] = a;
+        a = k[k[256] as usize];
+        b = k[259];
+        k[b as usize] = a;
+        a = k[256];
+        k[a as usize] = c;
+        b = k[258].wrapping_add(k[c as usize]);
+        k[258] = b;
+        c = k[k[259] as usize];
+        c = c
+            .wrapping_add(k[k[257] as usize])
+            .wrapping_add(k[k[260] as usize]);
+        a = k[b as usize].wrapping_add(k[k[256] as usize]);
+        k[k[c as usize] as usize] ^ k[a as usize]
+    }
+
+    // the key stream continues from the last encrypted and decrypted byte
+    fn decrypt(&mut self, byte: u8) -> u8 {
+        let decrypted = self.stream_byte() ^ byte;
+        self.key[259] = decrypted;
+        self.key[260] = byte;
+        decrypted
+    }
+
+    #[cfg(test)]
+    fn encrypt(&mut self, byte: u8) -> u8 {
+        let encrypted = self.stream_byte() ^ byte;
+        self.key[259] = byte;
+        self.key[260] = encrypted;
+        encrypted
+    }
+}
+
+// server flags of the server browsing list
+const PRIVATE_IP_FLAG: u8 = 0x02;
+const ICMP_IP_FLAG: u8 = 0x08;
+const NONSTANDARD_PORT_FLAG: u8 = 0x10;
+const NONSTANDARD_PRIVATE_PORT_FLAG: u8 = 0x20;
+const HAS_KEYS_FLAG: u8 = 0x40;
+const HAS_FULL_RULES_FLAG: u8 = 0x80;
+
+struct ListReader<'a> {
+    list: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ListReader<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self.list.get(self.position..self.position + length)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn port(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let length = self.list[self.position..]
+            .iter()
+            .position(|byte| *byte == 0)?;
+        let string = self.take(length)?;
+        self.position += 1;
+        Some(string)
+    }
+}
+
+// servers of a decrypted server browsing list, None until the end of the list arrived
+fn parse_browsing_list(list: &[u8]) -> Option<anyhow::Result<Vec<SocketAddr>>> {
+    let mut reader = ListReader { list, position: 0 };
+    // our address as the master sees it, then the query port of servers without their own
+    reader.take(4)?;
+    let default_port = reader.port()?;
+    if default_port == 0xFFFF {
+        let message = String::from_utf8_lossy(&list[reader.position..]).to_string();
+        return Some(Err(anyhow::anyhow!(
+            "master refused the list: {}",
+            message.trim_end_matches('\0')
+        )));
+    }
+
+    // requested without fields, masters still send the (empty) field and value lists
+    let mut field_types = vec![];
+    for _ in 0..reader.byte()? {
+        field_types.push(reader.byte()?);
+        reader.string()?;
+    }
+    for _ in 0..reader.byte()? {
+        reader.string()?;
+    }
+
+    let mut servers = vec![];
+    loop {
+        let flags = reader.byte()?;
+        let ip = reader.take(4)?;
+        if flags == 0 && ip == [0xFF; 4] {
+            return Some(Ok(servers));
+        }
+        let port = match flags & NONSTANDARD_PORT_FLAG != 0 {
+            true => reader.port()?,
+            false => default_port,
+        };
+        if flags & PRIVATE_IP_FLAG != 0 {
+            reader.take(4)?;
+        }
+        if flags & NONSTANDARD_PRIVATE_PORT_FLAG != 0 {
+            reader.take(2)?;
+        }
+        if flags & ICMP_IP_FLAG != 0 {
+            reader.take(4)?;
+        }
+        if flags & HAS_KEYS_FLAG != 0 {
+            for field_type in &field_types {
+                match field_type {
+                    // an index into the value list, 0xFF for a value that follows
+                    0 => {
+                        if reader.byte()? == 0xFF {
+                            reader.string()?;
+                        }
+                    }
+                    1 => {
+                        reader.take(1)?;
+                    }
+                    2 => {
+                        reader.take(2)?;
+                    }
+                    _ => return Some(Err(anyhow::anyhow!("unknown field type {}", field_type))),
+                }
+            }
+        }
+        if flags & HAS_FULL_RULES_FLAG != 0 {
+            return Some(Err(anyhow::anyhow!("unexpected full rules in the list")));
+        }
+        servers.push(SocketAddr::from((
+            Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]),
+            port,
+        )));
+    }
+}
+
+// list request without filter and fields, the rules come from the server queries
+fn browsing_request(config: &MasterConfig, challenge: &[u8; 8]) -> Vec<u8> {
+    // length, list request, protocol version 1, encoding version 3, game version 0
+    let mut request = vec![0, 0, 0x00, 0x01, 0x03, 0, 0, 0, 0];
+    // the game to list, then the game that asks
+    for _ in 0..2 {
+        request.extend_from_slice(config.game_name.as_bytes());
+        request.push(0);
+    }
+    request.extend_from_slice(challenge);
+    request.extend_from_slice(&[0, 0]);
+    request.extend_from_slice(&0u32.to_be_bytes());
+    let length = (request.len() as u16).to_be_bytes();
+    request[..2].copy_from_slice(&length);
+    request
+}
+
+async fn read_browsing_list(
+    stream: &mut TcpStream,
+    config: &MasterConfig,
+    challenge: &[u8; 8],
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let mut received = vec![];
+    let mut cipher: Option<Enctypex> = None;
+    let mut list = vec![];
+    let mut chunk = [0u8; 4096];
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            anyhow::bail!(
+                "{} closed the connection before the end of the list",
+                config.address
+            );
+        }
+        match cipher.as_mut() {
+            Some(cipher) => list.extend(chunk[..read].iter().map(|byte| cipher.decrypt(*byte))),
+            None => {
+                received.extend_from_slice(&chunk[..read]);
+                if let Some((mut new_cipher, start)) =
+                    Enctypex::from_header(config.game_key.as_bytes(), challenge, &received)
+                {
+                    list.extend(
+                        received[start..]
+                            .iter()
+                            .map(|byte| new_cipher.decrypt(*byte)),
+                    );
+                    cipher = Some(new_cipher);
+                }
+            }
+        }
+        if let Some(servers) = parse_browsing_list(&list) {
+            return servers;
+        }
+    }
+}
+
+// server list of the server browsing protocol, encrypted with the game key. the tests only
+// check it against a master that uses the cipher above, a master that doesn't agree fails
+// the list and the source falls back to the mongo list
+async fn browsing_list(config: &MasterConfig) -> anyhow::Result<Vec<SocketAddr>> {
+    if config.game_key.is_empty() {
+        anyhow::bail!("{} has no game key", config.game_name);
+    }
+    let list_timeout = config.timeout * 10;
+    let mut stream = timeout(list_timeout, TcpStream::connect(&config.address)).await??;
+    // printable, like the gamespy sdk makes them
+    let challenge: [u8; 8] = std::array::from_fn(|_| rand::random::<u8>() % 93 + 33);
+    stream
+        .write_all(&browsing_request(config, &challenge))
+        .await?;
+    timeout(
+        list_timeout,
+        read_browsing_list(&mut stream, config, &challenge),
+    )
+    .await?
+}
+
+pub async fn master_list(config: &MasterConfig) -> anyhow::Result<Vec<SocketAddr>> {
+    match config.list_protocol {
+        ListProtocol::Legacy => legacy_list(config).await,
+        ListProtocol::ServerBrowsing => browsing_list(config).await,
+    }
+}
+
+async fn receive(socket: &UdpSocket, query_timeout: Duration) -> anyhow::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; 8192];
+    let read = timeout(query_timeout, socket.recv(&mut buffer)).await??;
+    buffer.truncate(read);
+    Ok(buffer)
+}
+
+async fn exchange(
+    socket: &UdpSocket,
+    request: &[u8],
+    query_timeout: Duration,
+) -> anyhow::Result<Vec<u8>> {
+    socket.send(request).await?;
+    receive(socket, query_timeout).await
+}
+
+// "key\0value\0" pairs of one response packet, keys are lowercased
+fn parse_null_pairs(body: &[u8], rules: &mut HashMap<String, String>) {
+    let mut parts = body.split(|byte| *byte == 0);
+    while let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+        if key.is_empty() {
+            break;
+        }
+        rules.insert(
+            String::from_utf8_lossy(key).to_lowercase(),
+            String::from_utf8_lossy(value).to_string(),
+        );
+    }
+}
+
+// what a failed server query is counted as
+fn query_error_kind(e: &anyhow::Error) -> &'static str {
+    if e.is::<tokio::time::error::Elapsed>() {
+        "timeout"
+    } else if e.is::<std::io::Error>() {
+        "io"
+    } else {
+        "invalid"
+    }
+}
+
+// server rules of one server, keys are lowercased
+pub async fn query_server(
+    address: SocketAddr,
+    protocol: QueryProtocol,
+    query_timeout: Duration,
+) -> anyhow::Result<HashMap<String, String>> {
+    let socket =
+        UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))).await?;
+    socket.connect(address).await?;
+
+    if protocol == QueryProtocol::V1 {
+        let response = exchange(&socket, b"\\info\\", query_timeout).await?;
+        return Ok(parse_backslash_pairs(&String::from_utf8_lossy(&response)));
+    }
+
+    let id: [u8; 4] = rand::random();
+    let mut request = vec![0xFE, 0xFD, 0x00];
+    request.extend_from_slice(&id);
+    if protocol == QueryProtocol::V4 {
+        let mut challenge_request = vec![0xFE, 0xFD, 0x09];
+        challenge_request.extend_from_slice(&id);
+        let response = exchange(&socket, &challenge_request, query_timeout).await?;
+        if response.len() < 6 || response[0] != 0x09 {
+            anyhow::bail!("{} sent an invalid challenge", address);
+        }
+        let challenge = String::from_utf8_lossy(&response[5..])
+            .trim_end_matches('\0')
+            .parse::<i32>()?;
+        request.extend_from_slice(&challenge.to_be_bytes());
+    }
+    // server rules only, no player or team lists
+    request.extend_from_slice(&[0xFF, 0x00, 0x00]);
+
+    let mut response = exchange(&socket, &request, query_timeout).await?;
+    let mut packets: HashMap<u8, Vec<u8>> = HashMap::new();
+    let mut last_packet = None;
+    // split responses can arrive in any order
+    for _ in 0..=MAX_PACKETS {
+        if response.len() < 5 || response[0] != 0x00 || response[1..5] != id {
+            anyhow::bail!("{} sent an invalid response", address);
+        }
+        let body = &response[5..];
+        // split responses start with "splitnum\0", the packet number with 0x80 set on the
+        // last packet and the number of the section in the packet
+        if body.starts_with(b"splitnum\0") {
+            if body.len() < 11 {
+                anyhow::bail!("{} sent a truncated packet", address);
+            }
+            let number = body[9] & 0x7F;
+            if body[9] & 0x80 != 0 {
+                last_packet = Some(number);
+            }
+            packets.insert(number, body[11..].to_vec());
+        } else {
+            last_packet = Some(0);
+            packets.insert(0, body.to_vec());
+        }
+
+        if let Some(last_packet) = last_packet {
+            if (0..=last_packet).all(|number| packets.contains_key(&number)) {
+                let mut rules = HashMap::new();
+                for number in 0..=last_packet {
+                    parse_null_pairs(&packets[&number], &mut rules);
+                }
+                return Ok(rules);
+            }
+        }
+        response = receive(&socket, query_timeout).await?;
+    }
+    anyhow::bail!("{} sent more than {} packets", address, MAX_PACKETS)
+}
+
+fn to_old_game_server(address: SocketAddr, mut rules: HashMap<String, String>) -> OldGameServer {
+    OldGameServer {
+        server_ip: Some(address.ip().to_string()),
+        server_port: Some(address.port().to_string()),
+        hostport: rules.remove("hostport"),
+        numplayers: rules.remove("numplayers").unwrap_or("0".to_string()),
+        hostname: rules.remove("hostname"),
+        mapname: rules.remove("mapname"),
+        gametype: rules.remove("gametype"),
+        bfbc2_ip: None,
+        bfbc2_port: None,
+        bfbc2_name: None,
+        bfbc2_map: None,
+        bfbc2_mode: None,
+    }
+}
+
+// lists the servers from the master server and queries every one of them
+pub async fn gather_servers(
+    mongo_game_name: &str,
+    config: &MasterConfig,
+) -> error::Result<OldGameServerList> {
+    let addresses = master_list(config)
+        .await
+        .map_err(|e| anyhow::anyhow!("{} master list failed: {:#}", mongo_game_name, e))?;
+    let listed = addresses.len();
+    let results: Vec<anyhow::Result<OldGameServer>> = stream::iter(addresses)
+        .map(|address| async move {
+            query_server(address, config.query_protocol, config.timeout)
+                .await
+                .map(|rules| to_old_game_server(address, rules))
+        })
+        .buffer_unordered(config.concurrency)
+        .collect()
+        .await;
+
+    let mut server_list = vec![];
+    let mut query_errors: HashMap<&'static str, usize> = HashMap::new();
+    for result in results {
+        match result {
+            Ok(server) => server_list.push(server),
+            Err(e) => *query_errors.entry(query_error_kind(&e)).or_insert(0) += 1,
+        }
+    }
+    if !query_errors.is_empty() {
+        log::debug!(
+            "{} failed server queries: {:?}",
+            mongo_game_name,
+            query_errors
+        );
+    }
+
+    if server_list.is_empty() {
+        return Err(GatherError::UpstreamEmpty(format!(
+            "{} master listed {} servers, none answered",
+            mongo_game_name, listed
+        )));
+    }
+    if server_list.len() < listed {
+        log::info!(
+            "{} {} of {} listed servers answered",
+            mongo_game_name,
+            server_list.len(),
+            listed
+        );
+    }
+    Ok(OldGameServerList {
+        server_list,
+        time_stamp: Some(Utc::now()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const SECURE: &str = "ABCDEF";
+
+    // answers one list request like a legacy master, refuses a wrong validate answer
+    async fn spawn_legacy_master(game_key: &'static str, servers: Vec<SocketAddr>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(format!("\\basic\\\\secure\\{}", SECURE).as_bytes())
+                .await
+                .unwrap();
+            let mut request = vec![];
+            let mut chunk = [0u8; 1024];
+            while request
+                .windows(7)
+                .filter(|window| window == b"\\final\\")
+                .count()
+                < 2
+            {
+                let read = stream.read(&mut chunk).await.unwrap();
+                assert!(read > 0, "client closed before the list request");
+                request.extend_from_slice(&chunk[..read]);
+            }
+            let request = String::from_utf8_lossy(&request).to_string();
+            let validate = validate(SECURE.as_bytes(), game_key.as_bytes());
+            let response = match request.contains(&format!("\\validate\\{}\\", validate)) {
+                true => servers
+                    .iter()
+                    .map(|server| format!("\\ip\\{}", server))
+                    .collect::<String>(),
+                false => "\\error\\invalid validate".to_string(),
+            };
+            stream
+                .write_all(format!("{}\\final\\", response).as_bytes())
+                .await
+                .unwrap();
+        });
+        address
+    }
+
+    // answers one list request like a server browsing master. the first server gets the
+    // default port, the others their own port and a private address. the list is sent in
+    // two writes, so the cipher has to carry on between reads
+    async fn spawn_browsing_master(
+        game_name: &'static str,
+        game_key: &'static str,
+        servers: Vec<SocketAddr>,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut length = [0u8; 2];
+            stream.read_exact(&mut length).await.unwrap();
+            let mut request = vec![0u8; u16::from_be_bytes(length) as usize - 2];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[..3], [0x00, 0x01, 0x03]);
+            let mut reader = ListReader {
+                list: &request,
+                position: 7,
+            };
+            assert_eq!(reader.string(), Some(game_name.as_bytes()));
+            assert_eq!(reader.string(), Some(game_name.as_bytes()));
+            let challenge: [u8; 8] = reader.take(8).unwrap().try_into().unwrap();
+
+            let mut list = vec![127, 0, 0, 1];
+            list.extend_from_slice(&servers[0].port().to_be_bytes());
+            list.extend_from_slice(&[0, 0]);
+            for (i, server) in servers.iter().enumerate() {
+                let ip = match server.ip() {
+                    std::net::IpAddr::V4(ip) => ip.octets(),
+                    std::net::IpAddr::V6(_) => unreachable!(),
+                };
+                if i == 0 {
+                    list.push(0);
+                    list.extend_from_slice(&ip);
+                    continue;
+                }
+                list.push(NONSTANDARD_PORT_FLAG | PRIVATE_IP_FLAG);
+                list.extend_from_slice(&ip);
+                list.extend_from_slice(&server.port().to_be_bytes());
+                list.extend_from_slice(&[192, 168, 0, 2]);
+            }
+            list.extend_from_slice(&[0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+            let mut response = vec![3 ^ 0xEC, 1, 2, 3, 6 ^ 0xEA];
+            response.extend_from_slice(b"xyzzy!");
+            let (mut cipher, start) =
+                Enctypex::from_header(game_key.as_bytes(), &challenge, &response).unwrap();
+            assert_eq!(start, response.len());
+            response.extend(list.iter().map(|byte| cipher.encrypt(*byte)));
+            let (first, last) = response.split_at(start + 5);
+            stream.write_all(first).await.unwrap();
+            stream.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            stream.write_all(last).await.unwrap();
+        });
+        address
+    }
+
+    fn split_packet(id: &[u8], number: u8, pairs: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x00];
+        packet.extend_from_slice(id);
+        packet.extend_from_slice(b"splitnum\0");
+        packet.push(number);
+        packet.push(0x00);
+        packet.extend_from_slice(pairs);
+        packet
+    }
+
+    // answers queries like a game server, the v3/v4 rules are split over two packets
+    // that are sent last packet first
+    async fn spawn_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            loop {
+                let (read, peer) = socket.recv_from(&mut buffer).await.unwrap();
+                let request = &buffer[..read];
+                if request.starts_with(b"\\info\\") {
+                    let response = b"\\hostname\\v1 server\\numplayers\\12\\mapname\\wake\\gametype\\conquest\\hostport\\14567\\final\\\\queryid\\1.1";
+                    socket.send_to(response, peer).await.unwrap();
+                    continue;
+                }
+                let id = request[3..7].to_vec();
+                if request[2] == 0x09 {
+                    let mut response = vec![0x09];
+                    response.extend_from_slice(&id);
+                    response.extend_from_slice(b"-12345\0");
+                    socket.send_to(&response, peer).await.unwrap();
+                    continue;
+                }
+                // a v4 query has the challenge between the id and the rules request
+                if request.len() == 14 && request[7..11] != (-12345i32).to_be_bytes() {
+                    continue;
+                }
+                let last = split_packet(
+                    &id,
+                    0x81,
+                    b"mapname\0kubra\0gametype\0gpm_cq\0hostport\x0016567\0\0",
+                );
+                let first = split_packet(&id, 0x00, b"hostname\0v3 server\0numplayers\x0030\0");
+                socket.send_to(&last, peer).await.unwrap();
+                socket.send_to(&first, peer).await.unwrap();
+            }
+        });
+        address
+    }
+
+    fn test_config(
+        master: SocketAddr,
+        game_name: &str,
+        game_key: &str,
+        list_protocol: ListProtocol,
+        query_protocol: QueryProtocol,
+    ) -> MasterConfig {
+        MasterConfig {
+            address: master.to_string(),
+            game_name: game_name.to_string(),
+            game_key: game_key.to_string(),
+            list_protocol,
+            query_protocol,
+            timeout: Duration::from_millis(500),
+            concurrency: 4,
+        }
+    }
+
+    // lists a server that answers and one that never does
+    async fn gather_test_servers(
+        game_name: &'static str,
+        game_key: &'static str,
+        list_protocol: ListProtocol,
+        query_protocol: QueryProtocol,
+    ) -> OldGameServerList {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let servers = vec![spawn_server().await, silent.local_addr().unwrap()];
+        let master = match list_protocol {
+            ListProtocol::Legacy => spawn_legacy_master(game_key, servers).await,
+            ListProtocol::ServerBrowsing => {
+                spawn_browsing_master(game_name, game_key, servers).await
+            }
+        };
+        let config = test_config(master, game_name, game_key, list_protocol, query_protocol);
+        gather_servers(game_name, &config).await.unwrap()
+    }
+
+    #[test]
+    fn validate_known_answer() {
+        // computed with a separate implementation of aluigi's gsseckey (enctype 0)
+        assert_eq!(validate(SECURE.as_bytes(), b"hW6m9a"), "J5nGiiJ3");
+        assert_eq!(validate(b"ABCDEFG", b"hW6m9a"), "J5nGiiJ39gAA");
+        assert_eq!(validate(SECURE.as_bytes(), b""), "");
+    }
+
+    #[test]
+    fn browsing_list_needs_the_end_marker() {
+        let mut list = vec![127, 0, 0, 1, 0x75, 0x1C, 0, 0, 0, 1, 2, 3, 4];
+        assert!(parse_browsing_list(&list).is_none());
+        list.extend_from_slice(&[0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+        let servers = parse_browsing_list(&list).unwrap().unwrap();
+        assert_eq!(
+            servers,
+            vec!["1.2.3.4:29980".parse::<SocketAddr>().unwrap()]
+        );
+
+        let refused = [127, 0, 0, 1, 0xFF, 0xFF, b'n', b'o', 0];
+        assert!(parse_browsing_list(&refused).unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn gather_servers_v1() {
+        let list = gather_test_servers(
+            "bfield1942",
+            "HpWx9z",
+            ListProtocol::Legacy,
+            QueryProtocol::V1,
+        )
+        .await;
+        assert_eq!(list.server_list.len(), 1);
+        let server = &list.server_list[0];
+        assert_eq!(server.hostname.as_deref(), Some("v1 server"));
+        assert_eq!(server.numplayers, "12");
+        assert_eq!(server.mapname.as_deref(), Some("wake"));
+        assert_eq!(server.gametype.as_deref(), Some("conquest"));
+        assert_eq!(server.hostport.as_deref(), Some("14567"));
+        assert_eq!(server.server_ip.as_deref(), Some("127.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn gather_servers_v3_split() {
+        let list = gather_test_servers(
+            "battlefield2",
+            "hW6m9a",
+            ListProtocol::ServerBrowsing,
+            QueryProtocol::V3,
+        )
+        .await;
+        assert_eq!(list.server_list.len(), 1);
+        let server = &list.server_list[0];
+        assert_eq!(server.hostname.as_deref(), Some("v3 server"));
+        assert_eq!(server.numplayers, "30");
+        assert_eq!(server.mapname.as_deref(), Some("kubra"));
+        assert_eq!(server.gametype.as_deref(), Some("gpm_cq"));
+        assert_eq!(server.hostport.as_deref(), Some("16567"));
+    }
+
+    #[tokio::test]
+    async fn gather_servers_v4_challenge() {
+        let list = gather_test_servers(
+            "stella",
+            "M8o1Qw",
+            ListProtocol::ServerBrowsing,
+            QueryProtocol::V4,
+        )
+        .await;
+        assert_eq!(list.server_list.len(), 1);
+        assert_eq!(list.server_list[0].hostname.as_deref(), Some("v3 server"));
+        assert_eq!(list.server_list[0].numplayers, "30");
+    }
+
+    #[tokio::test]
+    async fn browsing_list_with_wrong_key_fails() {
+        let server = spawn_server().await;
+        let master = spawn_browsing_master("battlefield2", "hW6m9a", vec![server]).await;
+        let config = test_config(
+            master,
+            "battlefield2",
+            "wrong!",
+            ListProtocol::ServerBrowsing,
+            QueryProtocol::V3,
+        );
+        assert!(gather_servers("battlefield2", &config).await.is_err());
+    }
+
+    #[test]
+    fn master_config_needs_a_master() {
+        assert!(MasterConfig::from_env("bf2-unconfigured").is_none());
+    }
+}
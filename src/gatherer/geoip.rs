@@ -0,0 +1,67 @@
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    sync::LazyLock,
+};
+
+use maxminddb::{geoip2, Reader};
+
+// GEOIP_DATABASE points to a GeoLite2/GeoIP2 country or city mmdb file, lookups are
+// skipped when it isn't set
+static READER: LazyLock<Option<Reader<Vec<u8>>>> = LazyLock::new(|| {
+    let path = env::var("GEOIP_DATABASE").ok()?;
+    match Reader::open_readfile(&path) {
+        Ok(reader) => {
+            log::info!("Loaded geoip database {}", path);
+            Some(reader)
+        }
+        Err(e) => {
+            log::error!("Failed to load geoip database {}: {:#?}", path, e);
+            None
+        }
+    }
+});
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    // iso codes, like "DE" and "EU"
+    pub country: String,
+    pub continent: String,
+}
+
+impl Location {
+    // same region names as the gRPC games
+    pub fn region(&self) -> &'static str {
+        match &self.continent[..] {
+            "EU" => "EU",
+            "NA" => "NAm",
+            "SA" => "SAm",
+            "AS" => "Asia",
+            "OC" => "OC",
+            "AF" => "Afr",
+            _ => "Other",
+        }
+    }
+}
+
+// accepts "1.2.3.4" and "1.2.3.4:port"
+pub fn lookup(ip: &str) -> Option<Location> {
+    let reader = READER.as_ref()?;
+    let address = match ip.parse::<IpAddr>() {
+        Ok(address) => address,
+        Err(_) => ip.parse::<SocketAddr>().ok()?.ip(),
+    };
+    let country: geoip2::Country = reader.lookup(address).ok()?;
+    Some(Location {
+        country: country
+            .country
+            .and_then(|country| country.iso_code)
+            .unwrap_or_default()
+            .to_string(),
+        continent: country
+            .continent
+            .and_then(|continent| continent.code)
+            .unwrap_or_default()
+            .to_string(),
+    })
+}
@@ -0,0 +1,78 @@
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+fn env_secs(name: &str, default: u64) -> Duration {
+    Duration::from_secs(
+        env::var(name)
+            .ok()
+            .and_then(|secs| secs.parse::<u64>().ok())
+            .unwrap_or(default),
+    )
+}
+
+// one pooled client shared by all http gatherers, clones share the pool and host limits
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    per_host_limit: usize,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl HttpClient {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(env_secs("HTTP_CONNECT_TIMEOUT_SECONDS", 10))
+            .read_timeout(env_secs("HTTP_READ_TIMEOUT_SECONDS", 30))
+            .timeout(env_secs("HTTP_TIMEOUT_SECONDS", 60))
+            .pool_idle_timeout(env_secs("HTTP_POOL_IDLE_SECONDS", 90))
+            .pool_max_idle_per_host(
+                env::var("HTTP_POOL_MAX_IDLE_PER_HOST")
+                    .ok()
+                    .and_then(|idle| idle.parse::<usize>().ok())
+                    .unwrap_or(10),
+            )
+            .user_agent(env::var("HTTP_USER_AGENT").unwrap_or(format!(
+                "background-tasks-rust/{} (+https://github.com/Community-network)",
+                env!("CARGO_PKG_VERSION")
+            )));
+        // http(s):// or socks5:// url
+        if let Ok(proxy_url) = env::var("HTTP_PROXY_URL") {
+            builder = builder.proxy(reqwest::Proxy::all(proxy_url)?);
+        }
+
+        Ok(HttpClient {
+            client: builder.build()?,
+            per_host_limit: env::var("HTTP_MAX_CONCURRENT_PER_HOST")
+                .ok()
+                .and_then(|limit| limit.parse::<usize>().ok())
+                .unwrap_or(8)
+                .max(1),
+            hosts: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    pub fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        self.client.get(url)
+    }
+
+    // waits for a free slot for the host of the url, keep it until the body is read
+    pub async fn permit(&self, url: &str) -> anyhow::Result<OwnedSemaphorePermit> {
+        let host = reqwest::Url::parse(url)?
+            .host_str()
+            .unwrap_or_default()
+            .to_string();
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_host_limit)))
+            .clone();
+        Ok(semaphore.acquire_owned().await?)
+    }
+}
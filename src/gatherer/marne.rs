@@ -1,5 +1,12 @@
+use super::{
+    anomaly::Anomalies,
+    http::HttpClient,
+    retry::{retry, RetryPolicy},
+    validation,
+};
 use crate::{
     connectors::{influx_db, timescale_db::push_server},
+    error::{self, GatherError},
     structs::{
         marne::{MarneServerInfo, MarneServerList},
         results, server_info,
@@ -8,45 +15,33 @@ use crate::{
 use chrono::Utc;
 use regex::Regex;
 use sqlx::PgPool;
-use std::collections::HashMap;
+use std::{collections::HashMap, env};
 
-async fn gather_servers(game: &str) -> Vec<crate::structs::marne::MarneServerInfo> {
-    let client = reqwest::Client::new();
+async fn gather_servers(client: &HttpClient, game: &str) -> error::Result<Vec<MarneServerInfo>> {
+    let policy = RetryPolicy::from_env();
     let url = match game {
         "bfv" => "https://marne.io/api/v/srvlst/",
         _ => "https://marne.io/api/srvlst/",
     };
-    match client.get(url).send().await {
-        Ok(resp) => {
-            let json_string = resp.text().await.unwrap_or_default();
+    retry(
+        &policy,
+        &format!("{} marne public url", game),
+        || async move {
+            let _permit = client.permit(url).await?;
+            let json_string = client
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
             match json_string == "[]" {
-                true => match serde_json::from_str::<Vec<MarneServerInfo>>(&json_string) {
-                    // match resp.json::<MarneServerList>().await {
-                    Ok(json_res) => {
-                        return json_res;
-                    }
-                    Err(e) => {
-                        log::error!("{} marne public json array is incorrect: {:#?}", game, e);
-                        return vec![];
-                    }
-                },
-                false => match serde_json::from_str::<MarneServerList>(&json_string) {
-                    // match resp.json::<MarneServerList>().await {
-                    Ok(json_res) => {
-                        return json_res.servers;
-                    }
-                    Err(e) => {
-                        log::error!("{} marne public json is incorrect: {:#?}", game, e);
-                        return vec![];
-                    }
-                },
+                true => Ok(serde_json::from_str::<Vec<MarneServerInfo>>(&json_string)?),
+                false => Ok(serde_json::from_str::<MarneServerList>(&json_string)?.servers),
             }
-        }
-        Err(e) => {
-            log::error!("{} marne public url failed: {:#?}", game, e);
-            return vec![];
-        }
-    }
+        },
+    )
+    .await
 }
 
 async fn server_list_to_sum(
@@ -152,6 +147,8 @@ async fn server_list_to_sum(
             community_queue_amount: 0,
             spectator_amount: 0,
             community_spectator_amount: 0,
+            slot_amount: 0,
+            slotted_soldier_amount: 0,
 
             // unused
             dice_server_amount: 0,
@@ -159,16 +156,8 @@ async fn server_list_to_sum(
             dice_queue_amount: 0,
             dice_spectator_amount: 0,
         },
-        maps: HashMap::new(),
-        modes: HashMap::new(),
+        breakdown: results::Breakdown::default(),
         timestamp: Utc::now(),
-        map_players: HashMap::new(),
-        mode_players: HashMap::new(),
-        settings: HashMap::new(),
-        settings_players: HashMap::new(),
-        owner_platform: HashMap::new(),
-        playground: HashMap::new(),
-        playground_players: HashMap::new(),
     };
     let mut regions: HashMap<String, results::RegionResult> = HashMap::new();
     let mut server_stats: HashMap<String, Vec<server_info::ServerInfo>> = HashMap::new();
@@ -188,6 +177,19 @@ async fn server_list_to_sum(
             None => &server.map_name[..],
         };
         let map = maps.get(internal_map).unwrap_or(&internal_map).to_string();
+        let max_players = server_info::capacity(server.max_players);
+        let settings = server.settings();
+        let extra_keys: Vec<(results::Dimension, String)> = settings
+            .iter()
+            .map(|setting| (results::Dimension::Setting, setting.clone()))
+            .chain([(results::Dimension::Country, server.country.to_uppercase())])
+            .chain(
+                server
+                    .mods()
+                    .into_iter()
+                    .map(|server_mod| (results::Dimension::Mod, server_mod.key())),
+            )
+            .collect();
 
         regions
             .entry(server_region.clone())
@@ -196,25 +198,25 @@ async fn server_list_to_sum(
                 region.amounts.soldier_amount += server.current_players;
                 region.amounts.spectator_amount += server.current_spectators;
                 region
-                    .maps
-                    .entry(map.clone())
-                    .and_modify(|count| *count += 1)
-                    .or_insert(1);
-                region
-                    .map_players
-                    .entry(map.clone())
-                    .and_modify(|count| *count += server.current_players)
-                    .or_insert(server.current_players);
-                region
-                    .modes
-                    .entry(mode.clone())
-                    .and_modify(|count| *count += 1)
-                    .or_insert(1);
-                region
-                    .mode_players
-                    .entry(mode.clone())
-                    .and_modify(|count| *count += server.current_players)
-                    .or_insert(server.current_players);
+                    .amounts
+                    .add_slots(server.current_players, max_players);
+                region.breakdown.add(
+                    results::Dimension::Map,
+                    &map,
+                    server.current_players,
+                    max_players,
+                );
+                region.breakdown.add(
+                    results::Dimension::Mode,
+                    &mode,
+                    server.current_players,
+                    max_players,
+                );
+                for (dimension, key) in &extra_keys {
+                    region
+                        .breakdown
+                        .add(*dimension, key, server.current_players, max_players);
+                }
             })
             .or_insert({
                 results::RegionResult {
@@ -235,44 +237,51 @@ async fn server_list_to_sum(
                         spectator_amount: server.current_spectators,
                         dice_spectator_amount: 0,
                         community_spectator_amount: 0,
+                        slot_amount: max_players.unwrap_or_default(),
+                        slotted_soldier_amount: max_players.map_or(0, |_| server.current_players),
                     },
-                    maps: HashMap::from([(map.clone(), 1)]),
-                    modes: HashMap::from([(mode.clone(), 1)]),
+                    breakdown: results::Breakdown::server(
+                        [
+                            (results::Dimension::Map, &map[..]),
+                            (results::Dimension::Mode, &mode[..]),
+                        ]
+                        .into_iter()
+                        .chain(
+                            extra_keys
+                                .iter()
+                                .map(|(dimension, key)| (*dimension, &key[..])),
+                        ),
+                        server.current_players,
+                        max_players,
+                    ),
                     timestamp: Utc::now(),
-                    map_players: HashMap::from([(map.clone(), server.current_players)]),
-                    mode_players: HashMap::from([(mode.clone(), server.current_players)]),
-                    settings: HashMap::new(),
-                    settings_players: HashMap::new(),
-                    owner_platform: HashMap::new(),
-                    playground: HashMap::new(),
-                    playground_players: HashMap::new(),
                 }
             });
 
-        all_regions
-            .maps
-            .entry(map.clone())
-            .and_modify(|count| *count += 1)
-            .or_insert(1);
-        all_regions
-            .map_players
-            .entry(map.clone())
-            .and_modify(|count| *count += server.current_players)
-            .or_insert(server.current_players);
-        all_regions
-            .modes
-            .entry(mode.clone())
-            .and_modify(|count| *count += 1)
-            .or_insert(1);
-        all_regions
-            .mode_players
-            .entry(mode.clone())
-            .and_modify(|count| *count += server.current_players)
-            .or_insert(server.current_players);
+        all_regions.breakdown.add(
+            results::Dimension::Map,
+            &map,
+            server.current_players,
+            max_players,
+        );
+        all_regions.breakdown.add(
+            results::Dimension::Mode,
+            &mode,
+            server.current_players,
+            max_players,
+        );
+        for (dimension, key) in &extra_keys {
+            all_regions
+                .breakdown
+                .add(*dimension, key, server.current_players, max_players);
+        }
 
         all_regions.amounts.server_amount += 1;
         all_regions.amounts.soldier_amount += server.current_players;
         all_regions.amounts.spectator_amount += server.current_spectators;
+        all_regions
+            .amounts
+            .add_slots(server.current_players, max_players);
 
         let current_server_info = server_info::ServerInfo {
             guid: server.id.to_string(),
@@ -283,6 +292,8 @@ async fn server_list_to_sum(
             map,
             game_id: "".to_owned(),
             is_official: None,
+            max_players,
+            settings,
         };
         server_stats
             .entry(server_region)
@@ -295,11 +306,20 @@ async fn server_list_to_sum(
 }
 
 pub async fn push_marne(
+    client: &HttpClient,
     game: &str,
     pool: &PgPool,
     influx_client: &influxdb2::Client,
-) -> anyhow::Result<results::RegionResult> {
-    let found_servers = gather_servers(game).await;
+    anomalies: &Anomalies,
+) -> error::Result<results::RegionResult> {
+    let found_servers = gather_servers(client, game).await?;
+    let found_servers = validation::validate(
+        influx_client,
+        &format!("{}_marne", game),
+        "pc",
+        found_servers,
+    )
+    .await;
     let (regions, server_stats) = server_list_to_sum(found_servers).await;
     for (region, server_stat) in server_stats {
         match push_server(pool, &format!("{}_marne", game), &region, "pc", server_stat).await {
@@ -312,16 +332,41 @@ pub async fn push_marne(
             ),
         };
     }
-    match influx_db::push_to_database(influx_client, &format!("{}_marne", game), "pc", &regions)
+    match anomalies
+        .publish(influx_client, &format!("{}_marne", game), "pc", &regions)
         .await
     {
         Ok(_) => {}
         Err(e) => log::error!("{} Marne failed to push to influxdb: {:#?}", game, e),
     };
+    if let Some(all_regions) = regions.get("ALL") {
+        let top_mods = env::var("MARNE_TOP_MODS")
+            .ok()
+            .and_then(|top_mods| top_mods.parse::<usize>().ok())
+            .unwrap_or(10);
+        match influx_db::push_ranking(
+            influx_client,
+            &format!("{}_marne", game),
+            "pc",
+            all_regions,
+            results::Dimension::Mod,
+            top_mods,
+        )
+        .await
+        {
+            Ok(_) => {}
+            Err(e) => log::error!("{} Marne failed to push top mods: {:#?}", game, e),
+        };
+    }
 
     let result = match regions.get("ALL") {
         Some(result) => result,
-        None => anyhow::bail!("{} Marne has no ALL region!", game),
+        None => {
+            return Err(GatherError::UpstreamEmpty(format!(
+                "{} Marne has no ALL region!",
+                game
+            )))
+        }
     };
     Ok(result.to_owned())
 }
@@ -1,8 +1,16 @@
+pub mod anomaly;
 pub mod battlebit;
 pub mod battlefield_grpc_bf2042;
 pub mod battlefield_grpc_bf6;
 pub mod battlelog;
+pub mod circuit_breaker;
 pub mod companion;
+pub mod game_players;
+pub mod gamespy;
+pub mod geoip;
+pub mod http;
 pub mod marne;
 pub mod old_games;
+pub mod retry;
 pub mod server_manager;
+pub mod validation;
@@ -1,71 +1,212 @@
+use super::{anomaly::Anomalies, gamespy, geoip, validation};
 use crate::{
     connectors::{mongo::MongoClient, timescale_db::push_server},
-    structs::{old_games::OldGameServerList, results, server_info},
+    error::{self, GatherError},
+    structs::{
+        old_games::{OldGameServer, OldGameServerList},
+        results, server_info,
+    },
 };
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use futures::stream;
-use influxdb2::models::{data_point::DataPointError, DataPoint};
+use influxdb2::models::DataPoint;
+use serde::Serialize;
 use sqlx::PgPool;
-use std::collections::HashMap;
-
-pub fn build_data_point(
-    game_name: &str,
-    field: &str,
-    amount: i64,
-) -> Result<DataPoint, DataPointError> {
-    DataPoint::builder(game_name)
-        .tag("platform", "pc")
-        .tag("region", "ALL")
-        .tag("type", "amounts")
-        .field(field, amount)
-        .build()
+use std::{
+    collections::HashMap,
+    env,
+    sync::{LazyLock, Mutex, PoisonError},
+};
+
+// age of the last server list of every old game source, shown on the status endpoint
+static LIST_AGES: LazyLock<Mutex<HashMap<String, ListAge>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ListAge {
+    // "master" or "mongo"
+    pub source: &'static str,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(rename = "ageSeconds")]
+    pub age_seconds: Option<i64>,
+    pub stale: bool,
+}
+
+impl ListAge {
+    // the scraper doesn't write a time to every list, those can't be checked and are
+    // reported with an unknown age instead of failing the game
+    fn new(source: &'static str, updated_at: Option<DateTime<Utc>>) -> Self {
+        let max_age_minutes = env::var("OLD_GAMES_MAX_AGE_MINUTES")
+            .ok()
+            .and_then(|minutes| minutes.parse::<i64>().ok())
+            .unwrap_or(30);
+        let age_seconds = updated_at.map(|updated_at| (Utc::now() - updated_at).num_seconds());
+        ListAge {
+            source,
+            updated_at,
+            age_seconds,
+            stale: age_seconds.is_some_and(|age| age > max_age_minutes * 60),
+        }
+    }
 }
 
-pub async fn push_to_influx(
+pub fn list_ages() -> HashMap<String, ListAge> {
+    LIST_AGES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+async fn push_list_age(
     influx_client: &influxdb2::Client,
-    servers: &OldGameServerList,
-    soldier_amount: &i64,
     frontend_game_name: &str,
-) -> anyhow::Result<()> {
-    let bucket = "Game info";
-    let points = vec![
-        build_data_point(
-            frontend_game_name,
-            "serverAmount",
-            servers.server_list.len() as i64,
-        )?,
-        build_data_point(frontend_game_name, "soldierAmount", *soldier_amount)?,
-    ];
-    match influx_client
+    list_age: &ListAge,
+) -> error::Result<()> {
+    let mut point = DataPoint::builder(frontend_game_name)
+        .tag("platform", "pc")
+        .tag("region", "ALL")
+        .tag("type", "listAge")
+        .tag("source", list_age.source)
+        .field("stale", list_age.stale)
+        .field("ageKnown", list_age.age_seconds.is_some());
+    if let Some(age_seconds) = list_age.age_seconds {
+        point = point.field("ageSeconds", age_seconds);
+    }
+    influx_client
         .write_with_precision(
-            bucket,
-            stream::iter(points),
+            "Game info",
+            stream::iter(vec![point.build()?]),
             influxdb2::api::write::TimestampPrecision::Seconds,
         )
-        .await
-    {
-        Ok(_) => {}
-        Err(e) => log::error!(
-            "{} failed to push to influxdb: {:#?}",
-            frontend_game_name,
-            e
-        ),
-    };
+        .await?;
     Ok(())
 }
 
+// the bfbc2 list has come in empty or with half filled entries before, which broke the run.
+// malformed entries are handled like the malformed validation rule, a list that is mostly
+// malformed fails the game instead
+fn validate_bfbc2(
+    servers: OldGameServerList,
+    validation: &mut validation::Validation,
+) -> error::Result<OldGameServerList> {
+    if servers.server_list.is_empty() {
+        return Err(GatherError::UpstreamEmpty(
+            "bfbc2 server list is empty".to_string(),
+        ));
+    }
+    let listed = servers.server_list.len();
+    let (server_list, malformed): (Vec<OldGameServer>, Vec<OldGameServer>) =
+        servers.server_list.into_iter().partition(|server| {
+            !server.bfbc2_ip.as_deref().unwrap_or_default().is_empty()
+                && server.bfbc2_name.is_some()
+                && server.bfbc2_map.is_some()
+                && server.numplayers.parse::<i64>().is_ok()
+        });
+
+    validation.reject(validation::Rule::Malformed, malformed.len() as i64);
+    if malformed.len() * 2 > listed {
+        return Err(GatherError::Parse(format!(
+            "{} of {} bfbc2 servers are malformed",
+            malformed.len(),
+            listed
+        )));
+    }
+
+    let server_list = match validation.action(validation::Rule::Malformed) {
+        validation::Action::Drop => server_list,
+        _ => server_list.into_iter().chain(malformed).collect(),
+    };
+    Ok(OldGameServerList {
+        server_list,
+        time_stamp: servers.time_stamp,
+    })
+}
+
+async fn gather_from_mongo(
+    mongo_client: &mut MongoClient,
+    mongo_game_name: &str,
+    frontend_game_name: &str,
+) -> error::Result<OldGameServerList> {
+    match mongo_client.gather_old_title(mongo_game_name).await? {
+        Some(servers) => Ok(servers),
+        None => Err(GatherError::UpstreamEmpty(format!(
+            "No serverinfo gotten {}",
+            frontend_game_name
+        ))),
+    }
+}
+
 pub async fn push_old_games(
     pool: &PgPool,
     influx_client: &influxdb2::Client,
+    anomalies: &Anomalies,
     mongo_client: &mut MongoClient,
     mongo_game_name: &str,
     frontend_game_name: &str,
-) -> anyhow::Result<results::RegionResult> {
-    let servers = match mongo_client.gather_old_title(mongo_game_name).await? {
-        Some(servers) => servers,
-        None => anyhow::bail!("No serverinfo gotten {}", frontend_game_name),
+) -> error::Result<results::RegionResult> {
+    // the master server is asked directly when configured, mongo is filled by another process
+    let master_servers = match gamespy::MasterConfig::from_env(mongo_game_name) {
+        Some(config) => match gamespy::gather_servers(mongo_game_name, &config).await {
+            Ok(servers) => Some(servers),
+            Err(e) => {
+                log::warn!(
+                    "{} master server failed, falling back to mongo: {:#?}",
+                    mongo_game_name,
+                    e
+                );
+                None
+            }
+        },
+        None => None,
+    };
+    let (servers, source) = match master_servers {
+        Some(servers) => (servers, "master"),
+        None => (
+            gather_from_mongo(mongo_client, mongo_game_name, frontend_game_name).await?,
+            "mongo",
+        ),
     };
 
+    let mut validation = validation::Validation::new(validation::Validator::from_env());
+    let servers = match frontend_game_name {
+        "bfbc2" => match validate_bfbc2(servers, &mut validation) {
+            Ok(servers) => servers,
+            Err(e) => {
+                validation::report(
+                    influx_client,
+                    frontend_game_name,
+                    "pc",
+                    &validation.rejections,
+                )
+                .await;
+                return Err(e);
+            }
+        },
+        _ => servers,
+    };
+
+    // a dead scraper keeps the last list in mongo, that shouldn't count as a flat line
+    let list_age = ListAge::new(source, servers.time_stamp);
+    LIST_AGES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(mongo_game_name.to_string(), list_age.clone());
+    if let Err(e) = push_list_age(influx_client, frontend_game_name, &list_age).await {
+        log::error!(
+            "{} failed to push list age to influxdb: {:#?}",
+            frontend_game_name,
+            e
+        );
+    }
+    if list_age.stale {
+        return Err(GatherError::Stale(format!(
+            "{} server list is {} seconds old",
+            mongo_game_name,
+            list_age.age_seconds.unwrap_or_default()
+        )));
+    }
+
     let bfbc2_maps = HashMap::from([
         (
             "levels/bc1_harvest_day".to_string(),
@@ -132,19 +273,31 @@ pub async fn push_old_games(
         ("evolution".to_string(), "Evolution".to_string()),
     ]);
 
-    let mut server_stats = vec![];
-    let mut soldier_amount: i64 = 0;
+    // old games only have one list, the region comes from the server ip when geoip is set up
+    let mut server_stats: HashMap<String, Vec<server_info::ServerInfo>> = HashMap::new();
+    let mut regions: HashMap<String, results::RegionResult> =
+        HashMap::from([("ALL".to_string(), empty_region("ALL"))]);
     for server in &servers.server_list {
         let mut server_solier_amount = server.numplayers.parse::<i64>().unwrap_or_default();
         // bf2hub soldieramount can bug out for some reason
         if server_solier_amount > 200 {
             server_solier_amount = 0;
         }
-        if frontend_game_name == "bfbc2" {
+        let location = match frontend_game_name {
+            "bfbc2" => server.bfbc2_ip.as_deref(),
+            _ => server.server_ip.as_deref(),
+        }
+        .and_then(geoip::lookup);
+        let region = match &location {
+            Some(location) => location.region(),
+            None => "ALL",
+        };
+
+        let mut server_info = if frontend_game_name == "bfbc2" {
             let current_map: &String = &server.bfbc2_map.to_owned().unwrap_or_default();
             let current_mode: &String = &server.bfbc2_mode.to_owned().unwrap_or_default();
 
-            server_stats.push(server_info::ServerInfo {
+            server_info::ServerInfo {
                 guid: format!(
                     "{}:{}",
                     &server.bfbc2_ip.to_owned().unwrap_or_default(),
@@ -163,7 +316,9 @@ pub async fn push_old_games(
                     .to_string(),
                 game_id: "".to_owned(),
                 is_official: None,
-            });
+                max_players: None,
+                settings: vec![],
+            }
         } else {
             let mut guid = format!(
                 "{}:{}",
@@ -188,7 +343,7 @@ pub async fn push_old_games(
                 translated_mode = bf1942_modes.get(&*current_mode).unwrap_or(&&current_mode);
             }
 
-            server_stats.push(server_info::ServerInfo {
+            server_info::ServerInfo {
                 guid,
                 name: server.hostname.to_owned().unwrap_or_default(),
                 soldiers: server_solier_amount,
@@ -197,31 +352,92 @@ pub async fn push_old_games(
                 map: server.mapname.to_owned().unwrap_or_default(),
                 game_id: "".to_owned(),
                 is_official: None,
-            });
+                max_players: None,
+                settings: vec![],
+            }
+        };
+        if !validation.check(&mut server_info) {
+            continue;
         }
+        let server_solier_amount = server_info.soldiers;
 
-        soldier_amount += server_solier_amount;
+        let mut region_names = vec!["ALL"];
+        if region != "ALL" {
+            region_names.push(region);
+        }
+        for region_name in &region_names {
+            server_stats
+                .entry(region_name.to_string())
+                .or_default()
+                .push(server_info.clone());
+        }
+        for region_name in region_names {
+            let region_result = regions
+                .entry(region_name.to_string())
+                .or_insert_with(|| empty_region(region_name));
+            region_result.amounts.server_amount += 1;
+            region_result.amounts.soldier_amount += server_solier_amount;
+            if let Some(location) = &location {
+                region_result.breakdown.add(
+                    results::Dimension::Country,
+                    &location.country,
+                    server_solier_amount,
+                    None,
+                );
+            }
+        }
     }
 
-    match push_server(pool, frontend_game_name, "ALL", "pc", server_stats).await {
+    validation::report(
+        influx_client,
+        frontend_game_name,
+        "pc",
+        &validation.rejections,
+    )
+    .await;
+
+    for (region, server_stat) in server_stats {
+        match push_server(pool, frontend_game_name, &region, "pc", server_stat).await {
+            Ok(_) => {}
+            Err(e) => log::error!(
+                "{} region {} failed to push specific serverinfo: {:#?}",
+                frontend_game_name,
+                region,
+                e
+            ),
+        };
+    }
+
+    match anomalies
+        .publish(influx_client, frontend_game_name, "pc", &regions)
+        .await
+    {
         Ok(_) => {}
         Err(e) => log::error!(
-            "{} region failed to push specific serverinfo: {:#?}",
+            "{} failed to push to influxdb: {:#?}",
             frontend_game_name,
             e
         ),
     };
 
-    push_to_influx(influx_client, &servers, &soldier_amount, frontend_game_name).await?;
+    match regions.remove("ALL") {
+        Some(all_regions) => Ok(all_regions),
+        None => Err(GatherError::UpstreamEmpty(format!(
+            "{} has no ALL region!",
+            frontend_game_name
+        ))),
+    }
+}
 
-    Ok(results::RegionResult {
+fn empty_region(region: &str) -> results::RegionResult {
+    results::RegionResult {
         metadata: results::Metadata {
-            region: "ALL".to_string(),
+            region: region.to_string(),
             platform: "pc".to_string(),
         },
         amounts: results::RegionAmounts {
-            server_amount: servers.server_list.len() as i64,
-            soldier_amount: soldier_amount as i64,
+            server_amount: 0,
+            soldier_amount: 0,
             queue_amount: 0,
             spectator_amount: 0,
             dice_server_amount: 0,
@@ -232,16 +448,10 @@ pub async fn push_old_games(
             community_soldier_amount: 0,
             community_queue_amount: 0,
             community_spectator_amount: 0,
+            slot_amount: 0,
+            slotted_soldier_amount: 0,
         },
-        maps: HashMap::new(),
-        modes: HashMap::new(),
-        settings: HashMap::new(),
-        owner_platform: HashMap::new(),
+        breakdown: results::Breakdown::default(),
         timestamp: Utc::now(),
-        map_players: HashMap::new(),
-        mode_players: HashMap::new(),
-        settings_players: HashMap::new(),
-        playground: HashMap::new(),
-        playground_players: HashMap::new(),
-    })
+    }
 }
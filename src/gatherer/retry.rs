@@ -0,0 +1,196 @@
+use rand::Rng;
+use std::{env, future::Future, time::Duration};
+use tokio::time::sleep;
+
+use crate::error::{self, GatherError};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub retryable_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            retryable_statuses: vec![408, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        let default = RetryPolicy::default();
+        RetryPolicy {
+            max_attempts: env::var("HTTP_RETRY_ATTEMPTS")
+                .ok()
+                .and_then(|attempts| attempts.parse::<u32>().ok())
+                .unwrap_or(default.max_attempts)
+                .max(1),
+            base_delay: env::var("HTTP_RETRY_BASE_MS")
+                .ok()
+                .and_then(|millis| millis.parse::<u64>().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(default.base_delay),
+            max_delay: env::var("HTTP_RETRY_MAX_MS")
+                .ok()
+                .and_then(|millis| millis.parse::<u64>().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(default.max_delay),
+            // comma separated, e.g. "429,502,503"
+            retryable_statuses: match env::var("HTTP_RETRY_STATUSES") {
+                Ok(statuses) => statuses
+                    .split(',')
+                    .filter_map(|status| status.trim().parse::<u16>().ok())
+                    .collect(),
+                Err(_) => default.retryable_statuses,
+            },
+        }
+    }
+
+    // exponential backoff with full jitter
+    fn backoff(&self, attempt: u32) -> Duration {
+        let max_backoff = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=max_backoff.as_millis() as u64))
+    }
+
+    fn is_retryable(&self, error: &GatherError) -> bool {
+        match error {
+            GatherError::Network(e) => match e.status() {
+                Some(status) => self.retryable_statuses.contains(&status.as_u16()),
+                // connection errors, timeouts and broken bodies
+                None => true,
+            },
+            GatherError::RateLimited(_) => self.retryable_statuses.contains(&429),
+            // a truncated response can be fine on the next try
+            GatherError::Parse(_) | GatherError::UpstreamEmpty(_) | GatherError::Other(_) => true,
+            GatherError::Auth(_) | GatherError::Sink { .. } | GatherError::Stale(_) => false,
+        }
+    }
+}
+
+// runs the operation until it succeeds or the policy gives up, returning the last error.
+// use error_for_status() inside the operation so the status code can be checked
+pub async fn retry<T, F, Fut>(
+    policy: &RetryPolicy,
+    name: &str,
+    mut operation: F,
+) -> error::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = error::Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Ok(result) => return Ok(result),
+            Err(e) => {
+                if attempt >= policy.max_attempts || !policy.is_retryable(&e) {
+                    log::warn!("{} failed after {} attempt(s)", name, attempt);
+                    return Err(e);
+                }
+                let delay = policy.backoff(attempt);
+                log::warn!(
+                    "{} attempt {} failed, retrying in {:?}: {}",
+                    name,
+                    attempt,
+                    delay,
+                    e
+                );
+                sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max_delay() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            ..RetryPolicy::default()
+        };
+        for _ in 0..100 {
+            assert!(policy.backoff(1) <= Duration::from_millis(100));
+            assert!(policy.backoff(3) <= Duration::from_millis(400));
+            assert!(policy.backoff(10) <= Duration::from_millis(1000));
+            assert!(policy.backoff(u32::MAX) <= Duration::from_millis(1000));
+        }
+    }
+
+    #[test]
+    fn retryable_errors() {
+        let policy = RetryPolicy::default();
+        assert!(policy.is_retryable(&GatherError::Parse("truncated".into())));
+        assert!(policy.is_retryable(&GatherError::RateLimited("slow down".into())));
+        assert!(!policy.is_retryable(&GatherError::Auth("expired".into())));
+        assert!(!policy.is_retryable(&GatherError::Stale("old".into())));
+
+        let no_rate_limits = RetryPolicy {
+            retryable_statuses: vec![503],
+            ..RetryPolicy::default()
+        };
+        assert!(!no_rate_limits.is_retryable(&GatherError::RateLimited("slow down".into())));
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let attempts = AtomicU32::new(0);
+        let result = retry(&policy(3), "test", || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(GatherError::Parse("truncated".into())),
+                _ => Ok("done"),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), "done");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let attempts = AtomicU32::new(0);
+        let result: error::Result<()> = retry(&policy(2), "test", || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(GatherError::Parse("truncated".into()))
+        })
+        .await;
+        assert!(matches!(result, Err(GatherError::Parse(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_auth_errors() {
+        let attempts = AtomicU32::new(0);
+        let result: error::Result<()> = retry(&policy(5), "test", || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(GatherError::Auth("expired".into()))
+        })
+        .await;
+        assert!(result.unwrap_err().is_auth());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,437 @@
+use futures::stream;
+use influxdb2::models::DataPoint;
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+};
+
+use crate::structs::{
+    battlebit::BattlebitServer, battlelog::BattlelogServer, marne::MarneServerInfo,
+    server_info::ServerInfo,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rule {
+    NegativeCount,
+    OverCapacity,
+    DuplicateGuid,
+    EmptyName,
+    // records with missing or unparsable fields
+    Malformed,
+}
+
+impl Rule {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rule::NegativeCount => "negative_count",
+            Rule::OverCapacity => "over_capacity",
+            Rule::DuplicateGuid => "duplicate_guid",
+            Rule::EmptyName => "empty_name",
+            Rule::Malformed => "malformed",
+        }
+    }
+}
+
+// clamp fixes the record and keeps it, for rules that can't be fixed it keeps it as is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Drop,
+    Clamp,
+    Off,
+}
+
+// common view on the server records of the different gatherers
+pub trait Validate {
+    // None when the source has no unique id
+    fn guid(&self) -> Option<String>;
+    fn name(&self) -> &str;
+    fn max_players(&self) -> Option<i64>;
+    fn soldiers_mut(&mut self) -> &mut i64;
+    // every player count of the record, soldiers included
+    fn counts_mut(&mut self) -> Vec<&mut i64>;
+}
+
+impl Validate for BattlebitServer {
+    fn guid(&self) -> Option<String> {
+        None
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn max_players(&self) -> Option<i64> {
+        Some(self.max_players)
+    }
+    fn soldiers_mut(&mut self) -> &mut i64 {
+        &mut self.players
+    }
+    fn counts_mut(&mut self) -> Vec<&mut i64> {
+        vec![&mut self.players, &mut self.queue_players]
+    }
+}
+
+impl Validate for MarneServerInfo {
+    fn guid(&self) -> Option<String> {
+        Some(self.id.to_string())
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn max_players(&self) -> Option<i64> {
+        Some(self.max_players)
+    }
+    fn soldiers_mut(&mut self) -> &mut i64 {
+        &mut self.current_players
+    }
+    fn counts_mut(&mut self) -> Vec<&mut i64> {
+        vec![&mut self.current_players, &mut self.current_spectators]
+    }
+}
+
+impl Validate for BattlelogServer {
+    fn guid(&self) -> Option<String> {
+        Some(self.guid.clone())
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn max_players(&self) -> Option<i64> {
+        Some(self.max_soldiers)
+    }
+    fn soldiers_mut(&mut self) -> &mut i64 {
+        &mut self.soldier_amount
+    }
+    fn counts_mut(&mut self) -> Vec<&mut i64> {
+        vec![&mut self.soldier_amount, &mut self.queue_amount]
+    }
+}
+
+// for the gatherers that only build a ServerInfo per server
+impl Validate for ServerInfo {
+    fn guid(&self) -> Option<String> {
+        match self.guid.is_empty() {
+            true => None,
+            false => Some(self.guid.clone()),
+        }
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn max_players(&self) -> Option<i64> {
+        self.max_players
+    }
+    fn soldiers_mut(&mut self) -> &mut i64 {
+        &mut self.soldiers
+    }
+    fn counts_mut(&mut self) -> Vec<&mut i64> {
+        vec![&mut self.soldiers, &mut self.queue]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Validator {
+    actions: HashMap<Rule, Action>,
+}
+
+impl Validator {
+    // VALIDATION_<RULE>=drop|clamp|off, e.g. VALIDATION_OVER_CAPACITY=drop
+    pub fn from_env() -> Self {
+        let defaults = [
+            (Rule::NegativeCount, Action::Clamp),
+            (Rule::OverCapacity, Action::Clamp),
+            (Rule::DuplicateGuid, Action::Drop),
+            (Rule::EmptyName, Action::Drop),
+            (Rule::Malformed, Action::Drop),
+        ];
+        let actions = defaults
+            .into_iter()
+            .map(|(rule, default)| {
+                let action = match env::var(format!("VALIDATION_{}", rule.name().to_uppercase()))
+                    .unwrap_or_default()
+                    .to_lowercase()
+                    .as_str()
+                {
+                    "drop" => Action::Drop,
+                    "clamp" => Action::Clamp,
+                    "off" => Action::Off,
+                    _ => default,
+                };
+                (rule, action)
+            })
+            .collect();
+        Validator { actions }
+    }
+
+    pub fn action(&self, rule: Rule) -> Action {
+        *self.actions.get(&rule).unwrap_or(&Action::Off)
+    }
+
+    // returns the servers that passed and the amount of rejections per rule
+    pub fn validate<T: Validate>(&self, servers: Vec<T>) -> (Vec<T>, HashMap<&'static str, i64>) {
+        let mut validation = Validation::new(self.clone());
+        let valid_servers = servers
+            .into_iter()
+            .filter_map(|mut server| validation.check(&mut server).then_some(server))
+            .collect();
+        (valid_servers, validation.rejections)
+    }
+}
+
+// validates servers one by one, for gatherers that aggregate while they go through the list
+#[derive(Debug, Clone)]
+pub struct Validation {
+    validator: Validator,
+    seen_guids: HashSet<String>,
+    pub rejections: HashMap<&'static str, i64>,
+}
+
+impl Validation {
+    pub fn new(validator: Validator) -> Self {
+        Validation {
+            validator,
+            seen_guids: HashSet::new(),
+            rejections: HashMap::new(),
+        }
+    }
+
+    pub fn action(&self, rule: Rule) -> Action {
+        self.validator.action(rule)
+    }
+
+    // for rules the source checks itself
+    pub fn reject(&mut self, rule: Rule, amount: i64) {
+        if amount > 0 {
+            *self.rejections.entry(rule.name()).or_insert(0) += amount;
+        }
+    }
+
+    // false when the server has to be dropped, clamped values are fixed in place
+    pub fn check<T: Validate>(&mut self, server: &mut T) -> bool {
+        let mut broken_rules = vec![];
+        if server.counts_mut().iter().any(|count| **count < 0) {
+            broken_rules.push(Rule::NegativeCount);
+        }
+        if let Some(max_players) = server.max_players() {
+            if max_players > 0 && *server.soldiers_mut() > max_players {
+                broken_rules.push(Rule::OverCapacity);
+            }
+        }
+        // old games build "ip:port" keys, with a missing part they aren't unique
+        let guid = server
+            .guid()
+            .filter(|guid| !guid.split(':').any(|part| part.is_empty()));
+        if let Some(guid) = guid {
+            if !self.seen_guids.insert(guid) {
+                broken_rules.push(Rule::DuplicateGuid);
+            }
+        }
+        if server.name().trim().is_empty() {
+            broken_rules.push(Rule::EmptyName);
+        }
+
+        for rule in broken_rules {
+            match self.action(rule) {
+                Action::Off => continue,
+                Action::Drop => {
+                    self.reject(rule, 1);
+                    return false;
+                }
+                Action::Clamp => {
+                    self.reject(rule, 1);
+                    match rule {
+                        Rule::NegativeCount => {
+                            for count in server.counts_mut() {
+                                *count = (*count).max(0);
+                            }
+                        }
+                        Rule::OverCapacity => {
+                            if let Some(max_players) = server.max_players() {
+                                *server.soldiers_mut() = max_players;
+                            }
+                        }
+                        Rule::DuplicateGuid | Rule::EmptyName | Rule::Malformed => {}
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+// validates the gathered servers before aggregation, rejections get logged and sent to influx
+pub async fn validate<T: Validate>(
+    influx_client: &influxdb2::Client,
+    frontend_game_name: &str,
+    platform: &str,
+    servers: Vec<T>,
+) -> Vec<T> {
+    let (servers, rejections) = Validator::from_env().validate(servers);
+    report(influx_client, frontend_game_name, platform, &rejections).await;
+    servers
+}
+
+// logs the rejections and sends them to influx, also for sources that check their own payloads
+pub async fn report(
+    influx_client: &influxdb2::Client,
+    frontend_game_name: &str,
+    platform: &str,
+    rejections: &HashMap<&'static str, i64>,
+) {
+    if !rejections.is_empty() {
+        log::warn!(
+            "{} {} servers failed validation: {:?}",
+            frontend_game_name,
+            platform,
+            rejections
+        );
+    }
+    if let Err(e) = push_rejections(influx_client, frontend_game_name, platform, rejections).await {
+        log::error!(
+            "{} failed to push validation rejections to influxdb: {:#?}",
+            frontend_game_name,
+            e
+        );
+    }
+}
+
+async fn push_rejections(
+    influx_client: &influxdb2::Client,
+    frontend_game_name: &str,
+    platform: &str,
+    rejections: &HashMap<&'static str, i64>,
+) -> anyhow::Result<()> {
+    let mut points = vec![];
+    for rule in [
+        Rule::NegativeCount,
+        Rule::OverCapacity,
+        Rule::DuplicateGuid,
+        Rule::EmptyName,
+        Rule::Malformed,
+    ] {
+        points.push(
+            DataPoint::builder(frontend_game_name)
+                .tag("platform", platform)
+                .tag("type", "validation")
+                .tag("rule", rule.name())
+                .field("rejected", *rejections.get(rule.name()).unwrap_or(&0))
+                .build()?,
+        );
+    }
+    influx_client
+        .write_with_precision(
+            "Game info",
+            stream::iter(points),
+            influxdb2::api::write::TimestampPrecision::Seconds,
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(guid: &str, name: &str, soldiers: i64, queue: i64, max_players: i64) -> ServerInfo {
+        ServerInfo {
+            name: name.to_string(),
+            guid: guid.to_string(),
+            game_id: String::new(),
+            soldiers,
+            queue,
+            mode: String::new(),
+            map: String::new(),
+            is_official: None,
+            max_players: Some(max_players),
+            settings: vec![],
+        }
+    }
+
+    fn validator(action: Action) -> Validator {
+        Validator {
+            actions: [
+                Rule::NegativeCount,
+                Rule::OverCapacity,
+                Rule::DuplicateGuid,
+                Rule::EmptyName,
+                Rule::Malformed,
+            ]
+            .into_iter()
+            .map(|rule| (rule, action))
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn clamps_counts_and_capacity() {
+        let (servers, rejections) = validator(Action::Clamp).validate(vec![
+            server("a", "negative", -3, -1, 64),
+            server("b", "over", 80, 2, 64),
+        ]);
+        assert_eq!(servers.len(), 2);
+        assert_eq!((servers[0].soldiers, servers[0].queue), (0, 0));
+        assert_eq!((servers[1].soldiers, servers[1].queue), (64, 2));
+        assert_eq!(rejections["negative_count"], 1);
+        assert_eq!(rejections["over_capacity"], 1);
+    }
+
+    #[test]
+    fn drops_broken_servers() {
+        let (servers, rejections) = validator(Action::Drop).validate(vec![
+            server("a", "fine", 10, 0, 64),
+            server("a", "duplicate", 10, 0, 64),
+            server("b", " ", 10, 0, 64),
+            server("c", "over", 65, 0, 64),
+            server("d", "negative", 10, -1, 64),
+        ]);
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].name, "fine");
+        for rule in [
+            "duplicate_guid",
+            "empty_name",
+            "over_capacity",
+            "negative_count",
+        ] {
+            assert_eq!(rejections[rule], 1, "{}", rule);
+        }
+    }
+
+    #[test]
+    fn unknown_capacity_is_not_over_capacity() {
+        let (servers, rejections) =
+            validator(Action::Drop).validate(vec![server("a", "x", 80, 0, 0)]);
+        assert_eq!(servers.len(), 1);
+        assert!(rejections.is_empty());
+    }
+
+    #[test]
+    fn off_keeps_everything() {
+        let (servers, rejections) = validator(Action::Off).validate(vec![
+            server("a", "", -1, 0, 64),
+            server("a", "", 100, 0, 64),
+        ]);
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].soldiers, -1);
+        assert!(rejections.is_empty());
+    }
+
+    #[test]
+    fn skips_duplicates_of_incomplete_guids() {
+        let (servers, rejections) = validator(Action::Drop).validate(vec![
+            server(":23000", "one", 1, 0, 64),
+            server(":23000", "two", 1, 0, 64),
+            server("", "three", 1, 0, 64),
+            server("", "four", 1, 0, 64),
+            server("1.2.3.4:23000", "five", 1, 0, 64),
+            server("1.2.3.4:23000", "six", 1, 0, 64),
+        ]);
+        assert_eq!(servers.len(), 5);
+        assert_eq!(rejections["duplicate_guid"], 1);
+    }
+
+    #[test]
+    fn own_rejections_are_counted() {
+        let mut validation = Validation::new(validator(Action::Drop));
+        validation.reject(Rule::Malformed, 3);
+        validation.reject(Rule::Malformed, 0);
+        assert_eq!(validation.rejections["malformed"], 3);
+    }
+}
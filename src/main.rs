@@ -1,17 +1,20 @@
 mod check_ea_desktop_session;
 mod connectors;
+mod error;
 mod gatherer;
+mod shutdown;
 mod structs;
 
-use crate::connectors::influx_db;
+use crate::connectors::{influx_db, server_registry, spool, timescale_db};
 use bf_sparta::{cookie_request, sparta_api};
 use connectors::mongo::MongoClient;
 use gatherer::{
-    battlebit, battlefield_grpc_bf2042, battlefield_grpc_bf6, battlelog, companion, marne,
-    old_games,
+    anomaly::Anomalies, battlebit, battlefield_grpc_bf2042, battlefield_grpc_bf6, battlelog,
+    circuit_breaker::CircuitBreakers, companion, game_players, http::HttpClient, marne, old_games,
 };
 use grpc_rust::access_token::ea_desktop_access_token;
 use influxdb2::Client;
+use shutdown::Shutdown;
 use sqlx::postgres::PgPoolOptions;
 use std::{
     collections::HashMap,
@@ -39,7 +42,129 @@ async fn main() -> anyhow::Result<()> {
     flexi_logger::Logger::try_with_str("info")?.start()?;
     log::info!("Starting...");
 
+    match env::var("SPOOL_DIR") {
+        Ok(dir) => spool::init(&dir),
+        Err(_) => log::info!("SPOOL_DIR isn't set, failed writes won't be spooled"),
+    };
+    let circuit_breakers = CircuitBreakers::from_env();
+    let anomalies = Anomalies::from_env();
+    let http_client = HttpClient::from_env()?;
+    let influx_client = Client::new(
+        env::var("INFLUX_URL").expect("INFLUX_URL wasn't set"),
+        env::var("INFLUX_USER").expect("INFLUX_USER wasn't set"),
+        env::var("INFLUX_PASS").expect("INFLUX_PASS wasn't set"),
+    );
+    let status_circuit_breakers = circuit_breakers.clone();
+    let status_anomalies = anomalies.clone();
+    let accept_anomalies = anomalies.clone();
+    let anomaly_influx_client = influx_client.clone();
+
+    let pool = PgPoolOptions::new()
+        .test_before_acquire(false)
+        .connect(&env::var("DATABASE_URL").expect("DATABASE_URL wasn't set"))
+        .await?;
+    // creates and updates the tables in migrations/
+    sqlx::migrate!().run(&pool).await?;
+    let playground_pool = pool.clone();
+
     tokio::spawn(async move {
+        let status = warp::path("status").map(move || {
+            warp::reply::json(&serde_json::json!({
+                "circuitBreakers": status_circuit_breakers.states(),
+                "quarantined": status_anomalies.quarantined(),
+                "oldGameLists": old_games::list_ages(),
+            }))
+        });
+        // publishes the last quarantined result of a game, needs ANOMALY_ACCEPT_TOKEN as bearer token
+        let accept_anomaly = warp::post()
+            .and(warp::path!("anomaly" / "accept" / String / String))
+            .and(warp::header::optional::<String>("authorization"))
+            .then(
+                move |game: String, platform: String, authorization: Option<String>| {
+                    let influx_client = anomaly_influx_client.clone();
+                    let anomalies = accept_anomalies.clone();
+                    async move {
+                        let authorized = match env::var("ANOMALY_ACCEPT_TOKEN") {
+                            Ok(token) => authorization == Some(format!("Bearer {}", token)),
+                            Err(_) => false,
+                        };
+                        if !authorized {
+                            return warp::reply::with_status(
+                                "unauthorized".to_string(),
+                                warp::http::StatusCode::UNAUTHORIZED,
+                            );
+                        }
+                        let platform_result = match anomalies.accept(&game, &platform) {
+                            Some(platform_result) => platform_result,
+                            None => {
+                                return warp::reply::with_status(
+                                    format!("nothing quarantined for {}/{}", game, platform),
+                                    warp::http::StatusCode::NOT_FOUND,
+                                )
+                            }
+                        };
+                        match influx_db::push_to_database(
+                            &influx_client,
+                            &game,
+                            &platform,
+                            &platform_result,
+                        )
+                        .await
+                        {
+                            Ok(_) => {
+                                log::info!("Accepted quarantined result of {}/{}", game, platform);
+                                warp::reply::with_status(
+                                    format!("accepted {}/{}", game, platform),
+                                    warp::http::StatusCode::OK,
+                                )
+                            }
+                            Err(e) => {
+                                log::error!("Failed to push accepted result: {:#?}", e);
+                                warp::reply::with_status(
+                                    format!("failed to push {}/{}", game, platform),
+                                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                                )
+                            }
+                        }
+                    }
+                },
+            );
+        // ?limit= defaults to 50, at most 500
+        let playgrounds = warp::get()
+            .and(warp::path!("playgrounds" / String))
+            .and(warp::query::<HashMap<String, String>>())
+            .then(move |game: String, query: HashMap<String, String>| {
+                let pool = playground_pool.clone();
+                async move {
+                    if !["bf2042", "bf6"].contains(&&game[..]) {
+                        return warp::reply::with_status(
+                            warp::reply::json(&format!("no playground leaderboard for {}", game)),
+                            warp::http::StatusCode::NOT_FOUND,
+                        );
+                    }
+                    let limit = query
+                        .get("limit")
+                        .and_then(|limit| limit.parse::<i64>().ok())
+                        .unwrap_or(50)
+                        .clamp(1, 500);
+                    match timescale_db::playground_leaderboard(&pool, &game, limit).await {
+                        Ok(entries) => warp::reply::with_status(
+                            warp::reply::json(&entries),
+                            warp::http::StatusCode::OK,
+                        ),
+                        Err(e) => {
+                            log::error!("Failed to get playground leaderboard: {:#?}", e);
+                            warp::reply::with_status(
+                                warp::reply::json(&format!(
+                                    "failed to get playground leaderboard for {}",
+                                    game
+                                )),
+                                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                            )
+                        }
+                    }
+                }
+            });
         let hello = warp::any().map(move || {
             let last_update_i64 = last_update_clone.load(atomic::Ordering::Relaxed);
             let now_minutes = chrono::Utc::now().timestamp() / 60;
@@ -57,20 +182,31 @@ async fn main() -> anyhow::Result<()> {
                 )
             }
         });
-        warp::serve(hello).run(([0, 0, 0, 0], 3030)).await;
+        warp::serve(status.or(accept_anomaly).or(playgrounds).or(hello))
+            .run(([0, 0, 0, 0], 3030))
+            .await;
     });
 
-    let influx_client = Client::new(
-        env::var("INFLUX_URL").expect("INFLUX_URL wasn't set"),
-        env::var("INFLUX_USER").expect("INFLUX_USER wasn't set"),
-        env::var("INFLUX_PASS").expect("INFLUX_PASS wasn't set"),
-    );
     let mut mongo_client = MongoClient::connect().await?;
 
-    let pool = PgPoolOptions::new()
-        .test_before_acquire(false)
-        .connect(&env::var("DATABASE_URL").expect("DATABASE_URL wasn't set"))
-        .await?;
+    let cookie_audit_mins = env::var("COOKIE_AUDIT_MINUTES")
+        .ok()
+        .and_then(|mins| mins.parse::<u64>().ok())
+        .unwrap_or(60);
+    let mut audit_mongo_client = MongoClient::connect().await?;
+    tokio::spawn(async move {
+        loop {
+            match audit_mongo_client.audit_community_cookies().await {
+                Ok((valid_amount, total_amount)) => log::info!(
+                    "Cookie audit done, {}/{} cookies valid",
+                    valid_amount,
+                    total_amount
+                ),
+                Err(e) => log::error!("Cookie audit failed: {:#?}", e),
+            };
+            sleep(Duration::from_secs(cookie_audit_mins * 60)).await;
+        }
+    });
 
     let api_main_account = env::var("API_MAIN_ACCOUNT").expect("API_MAIN_ACCOUNT wasn't set");
     let api_bf2042_account = env::var("API_BF2042_ACCOUNT").expect("API_BF2042_ACCOUNT wasn't set");
@@ -151,249 +287,467 @@ async fn main() -> anyhow::Result<()> {
     };
 
     let empty_game_hash: HashMap<String, String> = HashMap::new();
-    let mut sessions: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut sessions: HashMap<String, HashMap<String, String>> =
+        match mongo_client.get_sessions().await {
+            Ok(result) => result,
+            Err(e) => {
+                log::warn!("Failed to load stored sessions, {}", e);
+                HashMap::new()
+            }
+        };
     let mut last_ran_detailed = chrono::Utc::now();
     let mut last_ran = chrono::Utc::now() - chrono::Duration::minutes(mins_between_runs);
 
+    match server_registry::load(&mut mongo_client).await {
+        Ok(known) => log::info!("Loaded {} known servers", known),
+        Err(e) => log::warn!("Failed to load server registry, starting empty: {:#?}", e),
+    };
+    match anomalies.load(&mut mongo_client).await {
+        Ok(baselines) => log::info!("Loaded {} anomaly baselines", baselines),
+        Err(e) => log::warn!("Failed to load anomaly baselines, starting empty: {:#?}", e),
+    };
+
     log::info!("Started");
 
+    let mut shutdown = Shutdown::listen();
+    let mut cancelled = false;
+
     loop {
+        if shutdown.is_requested() {
+            break;
+        }
         let run = last_ran.add(chrono::Duration::minutes(mins_between_runs)) <= chrono::Utc::now();
         if run {
-            log::info!("Starting new run");
-            last_ran = chrono::Utc::now();
+            let cycle = async {
+                log::info!("Starting new run");
+                last_ran = chrono::Utc::now();
 
-            match mongo_client.gather_managerinfo().await {
-                Ok(result) => {
-                    match gatherer::server_manager::save_server_manager_info(&influx_client, result)
+                // flush writes that failed while a sink was down
+                spool::replay(&pool, &influx_client).await;
+                if let Err(e) = spool::push_depth(&influx_client).await {
+                    log::error!("Failed to send spool depth to influxdb {:#?}", e);
+                }
+                if let Err(e) = server_registry::save(&mut mongo_client).await {
+                    log::error!("Failed to save server registry {:#?}", e);
+                }
+                if let Err(e) = anomalies.save(&mut mongo_client).await {
+                    log::error!("Failed to save anomaly baselines {:#?}", e);
+                }
+
+                match mongo_client.gather_managerinfo().await {
+                    Ok(result) => {
+                        match gatherer::server_manager::save_server_manager_info(
+                            &influx_client,
+                            result,
+                        )
                         .await
+                        {
+                            Ok(_) => {}
+                            Err(e) => {
+                                log::error!("Failed to send new manager info to influxdb {:#?}", e)
+                            }
+                        };
+                    }
+                    Err(e) => log::error!("Failed to send new manager info {:#?}", e),
+                };
+                log::info!("manager done");
+
+                let mut game_results: HashMap<String, results::RegionResult> = HashMap::new();
+                let mut failed_games: Vec<&str> = vec![];
+                let mut gather_errors: Vec<(String, &'static str)> = vec![];
+
+                let mut old_games = HashMap::from([
+                    ("bf2-playbf2", "playbf2"),
+                    ("bf2-bf2hub", "bf2hub"),
+                    ("bfield1942-bf1942org", "bfield1942"),
+                    ("bf2142-openspy", "bf2142"),
+                    ("bf2142-play2142", "play2142"),
+                    ("bfvietnam-qtracker", "bfvietnam"),
+                    ("bfvietnam-openspy", "openspy"),
+                ]);
+                // the bfbc2 list used to come in broken, it's only gathered when asked for
+                if env::var("OLD_GAMES_BFBC2").unwrap_or_default() == "true" {
+                    old_games.insert("bfbc2", "bfbc2");
+                }
+                for (key, value) in old_games.into_iter() {
+                    if !circuit_breakers.allow(key) {
+                        log::warn!("Skipping oldgame: {}, source is unavailable", key);
+                        failed_games.push(key);
+                        continue;
+                    }
+                    match old_games::push_old_games(
+                        &pool,
+                        &influx_client,
+                        &anomalies,
+                        &mut mongo_client,
+                        key,
+                        value,
+                    )
+                    .await
                     {
-                        Ok(_) => {}
+                        Ok(game_result) => {
+                            circuit_breakers.record_success(key);
+                            if anomalies.is_quarantined(value, "pc") {
+                                log::warn!("{} is quarantined, leaving it out of global", key);
+                            } else {
+                                game_results.insert(key.to_string(), game_result);
+                            }
+                        }
                         Err(e) => {
-                            log::error!("Failed to send new manager info to influxdb {:#?}", e)
+                            log::error!("Failed oldgame: {}, with reason: {:#?}", key, e);
+                            gather_errors.push((key.to_string(), e.kind()));
+                            circuit_breakers.record_failure(key);
+                            failed_games.push(key);
                         }
                     };
                 }
-                Err(e) => log::error!("Failed to send new manager info {:#?}", e),
-            };
-            log::info!("manager done");
-
-            let mut game_results: HashMap<String, results::RegionResult> = HashMap::new();
-            let mut failed_games: Vec<&str> = vec![];
-
-            let old_games = HashMap::from([
-                ("bf2-playbf2", "playbf2"),
-                ("bf2-bf2hub", "bf2hub"),
-                ("bfield1942-bf1942org", "bfield1942"),
-                ("bf2142-openspy", "bf2142"),
-                ("bf2142-play2142", "play2142"),
-                // ("bfbc2", "bfbc2"),
-                ("bfvietnam-qtracker", "bfvietnam"),
-                ("bfvietnam-openspy", "openspy"),
-            ]);
-            for (key, value) in old_games.into_iter() {
-                match old_games::push_old_games(
-                    &pool,
-                    &influx_client,
-                    &mut mongo_client,
-                    key,
-                    value,
-                )
-                .await
-                {
-                    Ok(game_result) => {
-                        game_results.insert(key.to_string(), game_result);
-                    }
-                    Err(e) => {
-                        log::error!("Failed oldgame: {}, with reason: {:#?}", key, e);
-                        failed_games.push(key);
-                    }
-                };
-            }
-            log::info!("oldgames done");
-
-            let sparta_games =
-                HashMap::from([("tunguska", "bf1"), ("casablanca", "bfv"), ("bf4", "bf4")]);
-            for (key, value) in sparta_games.into_iter() {
-                match companion::gather_companion(
-                    &pool,
-                    &influx_client,
-                    sessions.get(key).unwrap_or(&empty_game_hash).to_owned(),
-                    cookie.clone(),
-                    key,
-                    value,
-                )
-                .await
-                {
-                    Ok((session, platform_result)) => {
-                        sessions.insert(key.to_string(), session);
-                        game_results.insert(key.to_string(), platform_result);
-                    }
-                    Err(e) => {
-                        log::error!("Failed sparta_game: {}, with reason: {:#?}", key, e);
+                log::info!("oldgames done");
+
+                let sparta_games =
+                    HashMap::from([("tunguska", "bf1"), ("casablanca", "bfv"), ("bf4", "bf4")]);
+                for (key, value) in sparta_games.into_iter() {
+                    // bf4 is also gathered from battlelog, so breakers are kept per source
+                    let source = format!("{}_companion", key);
+                    if !circuit_breakers.allow(&source) {
+                        log::warn!("Skipping sparta_game: {}, source is unavailable", key);
                         failed_games.push(key);
+                        continue;
                     }
-                };
-            }
-            log::info!("sparta done");
+                    match companion::gather_companion(
+                        &pool,
+                        &influx_client,
+                        &anomalies,
+                        sessions.get(key).unwrap_or(&empty_game_hash).to_owned(),
+                        cookie.clone(),
+                        key,
+                        value,
+                    )
+                    .await
+                    {
+                        Ok((session, platform_result)) => {
+                            circuit_breakers.record_success(&source);
+                            if let Err(e) = mongo_client
+                                .push_sessions(
+                                    key,
+                                    sessions.get(key).unwrap_or(&empty_game_hash),
+                                    &session,
+                                    false,
+                                )
+                                .await
+                            {
+                                log::warn!("Failed to store session for {}: {:#?}", key, e);
+                            }
+                            sessions.insert(key.to_string(), session);
+                            // quarantined results are left out of the global array like failed games
+                            if anomalies.is_quarantined(value, "global") {
+                                log::warn!("{} is quarantined, leaving it out of global", key);
+                                failed_games.push(key);
+                            } else {
+                                game_results.insert(key.to_string(), platform_result);
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("Failed sparta_game: {}, with reason: {:#?}", key, e);
+                            gather_errors.push((source.clone(), e.kind()));
+                            circuit_breakers.record_failure(&source);
+                            failed_games.push(key);
+                        }
+                    };
+                }
+                log::info!("sparta done");
 
-            // pc only!
-            let battlelog_games = HashMap::from([
-                (
-                    "bf3",
-                    "https://battlelog.battlefield.com/bf3/servers/getAutoBrowseServers/",
-                ),
-                (
-                    "bf4",
-                    "https://battlelog.battlefield.com/bf4/servers/getServers/pc/",
-                ),
-                (
-                    "bfh",
-                    "https://battlelog.battlefield.com/bfh/servers/getServers/pc/",
-                ),
-            ]);
-            for (key, value) in battlelog_games {
-                match battlelog::gather_battlelog(&pool, &influx_client, key, value).await {
-                    Ok(game_result) => {
-                        game_results.insert(key.to_string(), game_result);
-                    }
-                    Err(e) => {
-                        log::error!("Failed battlelog_game: {}, with reason: {:#?}", key, e);
+                // pc only!
+                let battlelog_games = HashMap::from([
+                    (
+                        "bf3",
+                        "https://battlelog.battlefield.com/bf3/servers/getAutoBrowseServers/",
+                    ),
+                    (
+                        "bf4",
+                        "https://battlelog.battlefield.com/bf4/servers/getServers/pc/",
+                    ),
+                    (
+                        "bfh",
+                        "https://battlelog.battlefield.com/bfh/servers/getServers/pc/",
+                    ),
+                ]);
+                for (key, value) in battlelog_games {
+                    let source = format!("{}_battlelog", key);
+                    if !circuit_breakers.allow(&source) {
+                        log::warn!("Skipping battlelog_game: {}, source is unavailable", key);
                         failed_games.push(key);
+                        continue;
                     }
-                };
-            }
-            log::info!("battlelog done");
-
-            let run_detailed = last_ran_detailed
-                .add(chrono::Duration::hours(hours_between_detailed_runs))
-                <= chrono::Utc::now();
-            if run_detailed {
-                log::info!("Running grpc detailed");
-                last_ran_detailed = chrono::Utc::now();
-            }
+                    match battlelog::gather_battlelog(
+                        &http_client,
+                        &pool,
+                        &influx_client,
+                        &anomalies,
+                        key,
+                        value,
+                    )
+                    .await
+                    {
+                        Ok(game_result) => {
+                            circuit_breakers.record_success(&source);
+                            if anomalies.is_quarantined(key, "pc") {
+                                log::warn!("{} is quarantined, leaving it out of global", key);
+                                failed_games.push(key);
+                            } else {
+                                game_results.insert(key.to_string(), game_result);
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("Failed battlelog_game: {}, with reason: {:#?}", key, e);
+                            gather_errors.push((source.clone(), e.kind()));
+                            circuit_breakers.record_failure(&source);
+                            failed_games.push(key);
+                        }
+                    };
+                }
+                log::info!("battlelog done");
 
-            match battlefield_grpc_bf2042::gather_grpc(
-                &pool,
-                &influx_client,
-                sessions
-                    .get("kingston")
-                    .unwrap_or(&empty_game_hash)
-                    .to_owned(),
-                bf2042_cookie.clone(),
-                run_detailed,
-                ea_access_token.clone(),
-            )
-            .await
-            {
-                Ok((session, game_result)) => {
-                    sessions.insert("kingston".to_string(), session);
-                    game_results.insert("kingston".to_string(), game_result);
+                let run_detailed = last_ran_detailed
+                    .add(chrono::Duration::hours(hours_between_detailed_runs))
+                    <= chrono::Utc::now();
+                if run_detailed {
+                    log::info!("Running grpc detailed");
+                    last_ran_detailed = chrono::Utc::now();
                 }
-                Err(e) => {
-                    log::error!("Failed kingston_grpc, with reason: {:#?}", e);
-                    match ea_desktop_access_token(bf2042_cookie.clone()).await {
-                        Ok(res) => {
-                            (ea_access_token, bf2042_cookie) = res;
-                            mongo_client
-                                .push_new_cookies(
-                                    &api_bf2042_account,
-                                    &bf2042_cookie,
-                                    ea_access_token.clone(),
+
+                if !circuit_breakers.allow("kingston") {
+                    log::warn!("Skipping kingston_grpc, source is unavailable");
+                    failed_games.push("kingston");
+                } else {
+                    match battlefield_grpc_bf2042::gather_grpc(
+                        &pool,
+                        &influx_client,
+                        &anomalies,
+                        sessions
+                            .get("kingston")
+                            .unwrap_or(&empty_game_hash)
+                            .to_owned(),
+                        bf2042_cookie.clone(),
+                        run_detailed,
+                        ea_access_token.clone(),
+                    )
+                    .await
+                    {
+                        Ok((session, game_result)) => {
+                            circuit_breakers.record_success("kingston");
+                            if let Err(e) = mongo_client
+                                .push_sessions(
+                                    "kingston",
+                                    sessions.get("kingston").unwrap_or(&empty_game_hash),
+                                    &session,
+                                    false,
                                 )
-                                .await?;
+                                .await
+                            {
+                                log::warn!("Failed to store session for kingston: {:#?}", e);
+                            }
+                            sessions.insert("kingston".to_string(), session);
+                            if anomalies.is_quarantined("bf2042portal", "global") {
+                                log::warn!("kingston is quarantined, leaving it out of global");
+                                failed_games.push("kingston");
+                            } else {
+                                game_results.insert("kingston".to_string(), game_result);
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("Failed kingston_grpc, with reason: {:#?}", e);
+                            gather_errors.push(("kingston".to_string(), e.kind()));
+                            circuit_breakers.record_failure("kingston");
+                            // a rejected login needs a new access token
+                            if e.is_auth() {
+                                match ea_desktop_access_token(bf2042_cookie.clone()).await {
+                                    Ok(res) => {
+                                        (ea_access_token, bf2042_cookie) = res;
+                                        mongo_client
+                                            .push_new_cookies(
+                                                &api_bf2042_account,
+                                                &bf2042_cookie,
+                                                ea_access_token.clone(),
+                                            )
+                                            .await?;
+                                    }
+                                    Err(e) => {
+                                        log::error!("access_token for ea desktop failed: {:#?}", e)
+                                    }
+                                };
+                            }
+                            failed_games.push("kingston");
                         }
-                        Err(e) => log::error!("access_token for ea desktop failed: {:#?}", e),
                     };
-                    failed_games.push("kingston");
-                }
-            };
-            match battlefield_grpc_bf6::gather_grpc(
-                &pool,
-                &influx_client,
-                sessions
-                    .get("santiago")
-                    .unwrap_or(&empty_game_hash)
-                    .to_owned(),
-                bf2042_cookie.clone(),
-                run_detailed,
-                ea_access_token.clone(),
-            )
-            .await
-            {
-                Ok((session, game_result)) => {
-                    sessions.insert("santiago".to_string(), session);
-                    game_results.insert("santiago".to_string(), game_result);
                 }
-                Err(e) => {
-                    log::error!("Failed santiago_grpc, with reason: {:#?}", e);
-                    match ea_desktop_access_token(bf2042_cookie.clone()).await {
-                        Ok(res) => {
-                            (ea_access_token, bf2042_cookie) = res;
-                            mongo_client
-                                .push_new_cookies(
-                                    &api_bf2042_account,
-                                    &bf2042_cookie,
-                                    ea_access_token.clone(),
+                if !circuit_breakers.allow("santiago") {
+                    log::warn!("Skipping santiago_grpc, source is unavailable");
+                    failed_games.push("santiago");
+                } else {
+                    match battlefield_grpc_bf6::gather_grpc(
+                        &pool,
+                        &influx_client,
+                        &anomalies,
+                        sessions
+                            .get("santiago")
+                            .unwrap_or(&empty_game_hash)
+                            .to_owned(),
+                        bf2042_cookie.clone(),
+                        run_detailed,
+                        ea_access_token.clone(),
+                    )
+                    .await
+                    {
+                        Ok((session, game_result)) => {
+                            circuit_breakers.record_success("santiago");
+                            if let Err(e) = mongo_client
+                                .push_sessions(
+                                    "santiago",
+                                    sessions.get("santiago").unwrap_or(&empty_game_hash),
+                                    &session,
+                                    false,
                                 )
-                                .await?;
+                                .await
+                            {
+                                log::warn!("Failed to store session for santiago: {:#?}", e);
+                            }
+                            sessions.insert("santiago".to_string(), session);
+                            if anomalies.is_quarantined("bf6portal", "global") {
+                                log::warn!("santiago is quarantined, leaving it out of global");
+                                failed_games.push("santiago");
+                            } else {
+                                game_results.insert("santiago".to_string(), game_result);
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("Failed santiago_grpc, with reason: {:#?}", e);
+                            gather_errors.push(("santiago".to_string(), e.kind()));
+                            circuit_breakers.record_failure("santiago");
+                            // a rejected login needs a new access token
+                            if e.is_auth() {
+                                match ea_desktop_access_token(bf2042_cookie.clone()).await {
+                                    Ok(res) => {
+                                        (ea_access_token, bf2042_cookie) = res;
+                                        mongo_client
+                                            .push_new_cookies(
+                                                &api_bf2042_account,
+                                                &bf2042_cookie,
+                                                ea_access_token.clone(),
+                                            )
+                                            .await?;
+                                    }
+                                    Err(e) => {
+                                        log::error!("access_token for ea desktop failed: {:#?}", e)
+                                    }
+                                };
+                            }
+                            failed_games.push("santiago");
                         }
-                        Err(e) => log::error!("access_token for ea desktop failed: {:#?}", e),
                     };
-                    failed_games.push("santiago");
                 }
-            };
-            log::info!("grpc done");
-            for game in vec!["bf1", "bfv"] {
-                match marne::push_marne(game, &pool, &influx_client).await {
-                    Ok(game_result) => {
-                        game_results.insert(format!("{}_marne", game), game_result);
+                log::info!("grpc done");
+                for game in vec!["bf1", "bfv"] {
+                    let source = format!("{}_marne", game);
+                    if !circuit_breakers.allow(&source) {
+                        log::warn!("Skipping {} Marne, source is unavailable", game);
+                        continue;
                     }
-                    Err(e) => {
-                        log::error!("{} Marne failed with reason: {:#?}", game, e);
+                    match marne::push_marne(&http_client, game, &pool, &influx_client, &anomalies)
+                        .await
+                    {
+                        Ok(game_result) => {
+                            circuit_breakers.record_success(&source);
+                            if anomalies.is_quarantined(&source, "pc") {
+                                log::warn!("{} is quarantined, leaving it out of global", source);
+                            } else {
+                                game_results.insert(source, game_result);
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("{} Marne failed with reason: {:#?}", game, e);
+                            gather_errors.push((source.clone(), e.kind()));
+                            circuit_breakers.record_failure(&source);
+                        }
+                    };
+                }
+                log::info!("Marne done");
+
+                if run_detailed {
+                    for game in ["bf1", "bfv"] {
+                        match game_players::push_game_players(&http_client, &influx_client, game)
+                            .await
+                        {
+                            Ok(servers) => {
+                                log::info!("{} player lists of {} servers done", game, servers)
+                            }
+                            Err(e) => log::error!("{} player lists failed: {:#?}", game, e),
+                        };
                     }
-                };
-            }
-            log::info!("Marne done");
-
-            // if no games failed, make global array
-            if failed_games.iter().any(|&value| {
-                vec![
-                    "bf3",
-                    "bf4",
-                    "bfh",
-                    "tunguska",
-                    "casablanca",
-                    "kingston",
-                    "santiago",
-                ]
-                .contains(&value)
-            }) {
-                log::error!("1 of the important games failed to gather, skipping global array...");
-            } else {
-                let global_result =
-                    results::combine_region_players("global", "global", &game_results).await;
+                }
 
-                // influx
-                match influx_db::push_totals(&influx_client, &global_result).await {
-                    Ok(_) => log::info!("successfully made global array"),
-                    Err(e) => log::error!("Failed to push global games array: {:#?}", e),
-                };
-            }
-            log::info!("global done");
+                // if no games failed, make global array
+                if failed_games.iter().any(|&value| {
+                    vec![
+                        "bf3",
+                        "bf4",
+                        "bfh",
+                        "tunguska",
+                        "casablanca",
+                        "kingston",
+                        "santiago",
+                    ]
+                    .contains(&value)
+                }) {
+                    log::error!(
+                        "1 of the important games failed to gather, skipping global array..."
+                    );
+                } else {
+                    let global_result =
+                        results::combine_region_players("global", "global", &game_results).await;
 
-            match battlebit::push_battlebit(&pool, &influx_client).await {
-                Ok(_) => {}
-                Err(e) => {
-                    log::error!("Battlebit failed with reason: {:#?}", e);
+                    // influx
+                    match influx_db::push_totals(&influx_client, &global_result).await {
+                        Ok(_) => log::info!("successfully made global array"),
+                        Err(e) => log::error!("Failed to push global games array: {:#?}", e),
+                    };
                 }
-            };
-            log::info!("Battlebit done");
+                log::info!("global done");
 
-            last_update.store(
-                chrono::Utc::now().timestamp() / 60,
-                atomic::Ordering::Relaxed,
-            );
+                if !circuit_breakers.allow("battlebit") {
+                    log::warn!("Skipping Battlebit, source is unavailable");
+                } else {
+                    match battlebit::push_battlebit(&http_client, &pool, &influx_client, &anomalies)
+                        .await
+                    {
+                        Ok(_) => circuit_breakers.record_success("battlebit"),
+                        Err(e) => {
+                            log::error!("Battlebit failed with reason: {:#?}", e);
+                            gather_errors.push(("battlebit".to_string(), e.kind()));
+                            circuit_breakers.record_failure("battlebit");
+                        }
+                    };
+                }
+                log::info!("Battlebit done");
+
+                if let Err(e) = influx_db::push_gather_errors(&influx_client, &gather_errors).await
+                {
+                    log::error!("Failed to send gather errors to influxdb {:#?}", e);
+                }
+
+                last_update.store(
+                    chrono::Utc::now().timestamp() / 60,
+                    atomic::Ordering::Relaxed,
+                );
+                Ok::<(), anyhow::Error>(())
+            };
+            tokio::select! {
+                result = cycle => result?,
+                _ = shutdown.deadline_passed() => {
+                    log::warn!("Shutdown deadline reached, cancelling the current run");
+                    cancelled = true;
+                    break;
+                }
+            }
         } else {
             // rotating ea desktop token
             // for i in 6..11 {
@@ -473,27 +827,60 @@ async fn main() -> anyhow::Result<()> {
             .await
             {
                 Ok(session) => {
+                    if let Err(e) = mongo_client
+                        .push_sessions(
+                            "kingston",
+                            sessions.get("kingston").unwrap_or(&empty_game_hash),
+                            &session,
+                            true,
+                        )
+                        .await
+                    {
+                        log::warn!("Failed to store session for kingston: {:#?}", e);
+                    }
                     sessions.insert("kingston".to_string(), session);
                     log::info!("kingston: Finished auth check!");
                 }
                 Err(e) => {
                     log::error!("Failed kingston_grpc, auth_check reason: {:#?}", e);
-                    match ea_desktop_access_token(bf2042_cookie.clone()).await {
-                        Ok(res) => {
-                            (ea_access_token, bf2042_cookie) = res;
-                            mongo_client
-                                .push_new_cookies(
-                                    &api_bf2042_account,
-                                    &bf2042_cookie,
-                                    ea_access_token.clone(),
-                                )
-                                .await?;
-                        }
-                        Err(e) => log::error!("access_token for ea desktop failed: {:#?}", e),
-                    };
+                    // a rejected login needs a new access token
+                    if e.is_auth() {
+                        match ea_desktop_access_token(bf2042_cookie.clone()).await {
+                            Ok(res) => {
+                                (ea_access_token, bf2042_cookie) = res;
+                                mongo_client
+                                    .push_new_cookies(
+                                        &api_bf2042_account,
+                                        &bf2042_cookie,
+                                        ea_access_token.clone(),
+                                    )
+                                    .await?;
+                            }
+                            Err(e) => log::error!("access_token for ea desktop failed: {:#?}", e),
+                        };
+                    }
                 }
             };
-            sleep(Duration::from_secs(30)).await;
+            tokio::select! {
+                _ = sleep(Duration::from_secs(30)) => {}
+                _ = shutdown.requested() => {}
+            }
         }
     }
+
+    log::info!("Shutting down...");
+    // a cancelled run was dropped in the middle of its writes, the next start replays the spool
+    if !cancelled {
+        spool::replay(&pool, &influx_client).await;
+    }
+    if let Err(e) = server_registry::save(&mut mongo_client).await {
+        log::error!("Failed to save server registry {:#?}", e);
+    }
+    if let Err(e) = anomalies.save(&mut mongo_client).await {
+        log::error!("Failed to save anomaly baselines {:#?}", e);
+    }
+    pool.close().await;
+    mongo_client.shutdown().await;
+    log::info!("Stopped");
+    Ok(())
 }
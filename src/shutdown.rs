@@ -0,0 +1,75 @@
+use std::{env, time::Duration};
+use tokio::{sync::watch, time::sleep};
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(e) => {
+                    log::error!("Failed to listen for SIGTERM: {:#?}", e);
+                    let _ = tokio::signal::ctrl_c().await;
+                    return;
+                }
+            };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>,
+    pub deadline: Duration,
+}
+
+impl Shutdown {
+    // starts listening for SIGTERM/SIGINT. docker kills the container 10 seconds after
+    // SIGTERM, raise its stop_grace_period before raising SHUTDOWN_DEADLINE_SECONDS
+    pub fn listen() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            log::info!("Shutdown requested, not starting new runs");
+            let _ = sender.send(true);
+        });
+        Shutdown {
+            receiver,
+            deadline: Duration::from_secs(
+                env::var("SHUTDOWN_DEADLINE_SECONDS")
+                    .ok()
+                    .and_then(|secs| secs.parse::<u64>().ok())
+                    .unwrap_or(8),
+            ),
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    pub async fn requested(&mut self) {
+        if self
+            .receiver
+            .wait_for(|requested| *requested)
+            .await
+            .is_err()
+        {
+            // the signal listener is gone, so shutdown can't be requested anymore
+            std::future::pending::<()>().await;
+        }
+    }
+
+    // resolves when the in-flight work has run out of time after a shutdown request
+    pub async fn deadline_passed(&mut self) {
+        self.requested().await;
+        sleep(self.deadline).await;
+    }
+}
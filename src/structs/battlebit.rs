@@ -31,3 +31,17 @@ pub struct BattlebitServer {
     #[serde(rename = "Build")]
     pub build: String,
 }
+
+impl BattlebitServer {
+    // "name:value" pairs, used as setting breakdown keys and stored per server
+    pub fn settings(&self) -> Vec<String> {
+        vec![
+            format!("hz:{}", self.hz),
+            format!("mapSize:{}", self.map_size),
+            format!("dayNight:{}", self.day_night),
+            format!("antiCheat:{}", self.anti_cheat),
+            format!("password:{}", self.has_password),
+            format!("build:{}", self.build),
+        ]
+    }
+}
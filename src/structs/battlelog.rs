@@ -13,6 +13,8 @@ pub struct BattlelogServer {
     pub queue_amount: i64,
     #[serde(rename = "soldierAmount")]
     pub soldier_amount: i64,
+    #[serde(rename = "maxSoldiers", default)]
+    pub max_soldiers: i64,
     pub map: String,
 }
 
@@ -26,13 +28,113 @@ pub struct TeamInfo {
     pub players: HashMap<String, PlayerInfo>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TeamTickets {
+    #[serde(default)]
+    pub tickets: i64,
+    #[serde(rename = "ticketsMax", default)]
+    pub tickets_max: i64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Snapshot {
     #[serde(rename = "teamInfo")]
     pub team_info: HashMap<String, TeamInfo>,
+    // per team, only in ticket based modes
+    #[serde(default)]
+    pub conquest: HashMap<String, TeamTickets>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Keeper {
     pub snapshot: Snapshot,
 }
+
+// what a keeper snapshot says about one server. every listed player counts as a soldier,
+// like before, the snapshot format isn't documented enough to split out spectators
+#[derive(Debug, Clone, Default)]
+pub struct KeeperSummary {
+    pub soldiers: i64,
+    // players in the biggest team minus the smallest
+    pub team_difference: i64,
+    pub ticket_difference: Option<i64>,
+}
+
+impl From<Keeper> for KeeperSummary {
+    fn from(keeper: Keeper) -> Self {
+        let mut summary = KeeperSummary::default();
+        let mut team_sizes = vec![];
+        for team_info in keeper.snapshot.team_info.values() {
+            summary.soldiers += team_info.players.len() as i64;
+            // empty teams are unused slots in the snapshot
+            if !team_info.players.is_empty() {
+                team_sizes.push(team_info.players.len() as i64);
+            }
+        }
+        summary.team_difference =
+            team_sizes.iter().max().unwrap_or(&0) - team_sizes.iter().min().unwrap_or(&0);
+
+        let tickets: Vec<i64> = keeper
+            .snapshot
+            .conquest
+            .values()
+            .map(|team| team.tickets)
+            .collect();
+        if tickets.len() >= 2 {
+            summary.ticket_difference =
+                Some(tickets.iter().max().unwrap_or(&0) - tickets.iter().min().unwrap_or(&0));
+        }
+        summary
+    }
+}
+
+// how many populated servers had their soldier count checked against keeper
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeeperVerification {
+    pub checked: i64,
+    pub failed: i64,
+    // summed over the servers with a snapshot
+    pub team_difference: i64,
+    pub ticket_difference: i64,
+    // servers in a ticket based mode
+    pub ticket_servers: i64,
+}
+
+impl KeeperVerification {
+    pub fn average_team_difference(&self) -> f64 {
+        let verified = self.checked - self.failed;
+        match verified > 0 {
+            true => self.team_difference as f64 / verified as f64,
+            false => 0.0,
+        }
+    }
+
+    pub fn average_ticket_difference(&self) -> f64 {
+        match self.ticket_servers > 0 {
+            true => self.ticket_difference as f64 / self.ticket_servers as f64,
+            false => 0.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CrawlReport {
+    pub pages: i64,
+    pub rows: i64,
+    pub servers: i64,
+    // rows of servers already seen on an earlier page
+    pub duplicates: i64,
+    // the server list ran out before the page limit was hit
+    pub exhausted: bool,
+    pub keeper: Option<KeeperVerification>,
+}
+
+impl CrawlReport {
+    // servers shift between pages while crawling, each duplicate roughly means one missed server
+    pub fn completeness(&self) -> f64 {
+        if self.servers + self.duplicates == 0 {
+            return 1.0;
+        }
+        self.servers as f64 / (self.servers + self.duplicates) as f64
+    }
+}
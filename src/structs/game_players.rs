@@ -1,44 +1,114 @@
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct GamePlayerInfo {
     pub rank: u64,
     pub latency: u64,
+    #[serde(default)]
     pub slot: u64,
+    #[serde(default)]
     pub join_time: u64,
+    #[serde(default)]
     pub localization: String,
+    #[serde(default)]
     pub user_id: u64,
+    #[serde(default)]
     pub player_id: u64,
     pub name: String,
+    // only sent for crossplay games
+    #[serde(default)]
     pub platform: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct GamePlayers {
-    pub players: std::collections::HashMap<u64, GamePlayerInfo>,
-    pub team_1: Vec<u64>,
-    pub team_2: Vec<u64>,
-    pub spectators: Vec<u64>,
-    pub loading: Vec<u64>,
-    pub que: Vec<u64>,
-    pub server_info: ServerInfo,
+pub struct PlayerListTeam {
+    #[serde(default)]
+    pub players: Vec<GamePlayerInfo>,
 }
 
+// player list of one server from api.gametools.network/{game}/players/?gameid=
 #[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct ServerInfo {
-    pub server_name: String,
-    pub admins: Vec<String>,
-    pub country: String,
-    pub description: String,
-    pub experience: String,
-    pub fairfight: String,
-    pub level: String,
-    pub mode: String,
-    pub lowrankonly: String,
-    pub maps: Vec<String>,
-    pub owner: String,
-    pub settings: Vec<String>,
-    pub vips: Vec<String>,
-    pub region: String,
-    pub servertype: String,
+pub struct PlayerList {
+    pub teams: Vec<PlayerListTeam>,
+    #[serde(default)]
+    pub loading: Vec<GamePlayerInfo>,
+    #[serde(default)]
+    pub que: Vec<GamePlayerInfo>,
+}
+
+// per region aggregate of the player lists, without names or ids
+#[derive(Debug, Default, Clone)]
+pub struct RegionPlayerSummary {
+    pub servers: i64,
+    pub players: i64,
+    pub rank_total: i64,
+    pub team_difference_total: i64,
+    pub latency: HashMap<&'static str, i64>,
+    pub platforms: HashMap<String, i64>,
+}
+
+// everything from 250 up is "250+"
+const LATENCY_BUCKETS: [(u64, &str); 4] = [
+    (50, "0-49"),
+    (100, "50-99"),
+    (150, "100-149"),
+    (250, "150-249"),
+];
+
+impl RegionPlayerSummary {
+    // players in the teams, loading and queued players aren't counted
+    pub fn add(&mut self, player_list: &PlayerList) {
+        self.servers += 1;
+        let team_size = |team: usize| {
+            player_list
+                .teams
+                .get(team)
+                .map(|team| team.players.len() as i64)
+                .unwrap_or_default()
+        };
+        self.team_difference_total += (team_size(0) - team_size(1)).abs();
+        for player in player_list.teams.iter().flat_map(|team| &team.players) {
+            self.players += 1;
+            self.rank_total += player.rank as i64;
+            let bucket = LATENCY_BUCKETS
+                .iter()
+                .find(|(below, _)| player.latency < *below)
+                .map_or("250+", |(_, bucket)| *bucket);
+            *self.latency.entry(bucket).or_insert(0) += 1;
+            let platform = match player.platform.is_empty() {
+                true => "unknown".to_string(),
+                false => player.platform.clone(),
+            };
+            *self.platforms.entry(platform).or_insert(0) += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &RegionPlayerSummary) {
+        self.servers += other.servers;
+        self.players += other.players;
+        self.rank_total += other.rank_total;
+        self.team_difference_total += other.team_difference_total;
+        for (bucket, players) in &other.latency {
+            *self.latency.entry(bucket).or_insert(0) += players;
+        }
+        for (platform, players) in &other.platforms {
+            *self.platforms.entry(platform.clone()).or_insert(0) += players;
+        }
+    }
+
+    pub fn average_rank(&self) -> f64 {
+        match self.players > 0 {
+            true => self.rank_total as f64 / self.players as f64,
+            false => 0.0,
+        }
+    }
+
+    // average difference in players between the two teams
+    pub fn average_team_difference(&self) -> f64 {
+        match self.servers > 0 {
+            true => self.team_difference_total as f64 / self.servers as f64,
+            false => 0.0,
+        }
+    }
 }
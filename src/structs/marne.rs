@@ -1,4 +1,4 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Player {
@@ -56,6 +56,48 @@ pub struct MarneServerInfo {
     pub current_spectators: i64,
     pub region: String,
     pub country: String,
+    #[serde(rename = "modList", default, deserialize_with = "lenient_mod_list")]
+    pub mod_list: Option<ModType>,
+}
+
+// a mod list in an unexpected shape shouldn't drop the whole server list
+fn lenient_mod_list<'de, D>(deserializer: D) -> Result<Option<ModType>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(serde_json::from_value(value).ok())
+}
+
+impl MarneServerInfo {
+    // "name:value" pairs, used as setting breakdown keys and stored per server
+    pub fn settings(&self) -> Vec<String> {
+        vec![
+            format!("modded:{}", self.is_modded),
+            format!("tickRate:{}", self.tick_rate),
+            format!("password:{}", self.password != 0),
+            format!("needSameMods:{}", self.need_same_mods != 0),
+            format!("natType:{}", self.nat_type),
+        ]
+    }
+
+    // the list is sometimes sent as a json encoded string
+    pub fn mods(&self) -> Vec<Mod> {
+        match &self.mod_list {
+            Some(ModType::Vec(mods)) => mods.clone(),
+            Some(ModType::String(mods)) => serde_json::from_str(mods).unwrap_or_default(),
+            None => vec![],
+        }
+    }
+}
+
+impl Mod {
+    pub fn key(&self) -> String {
+        match self.version.is_empty() {
+            true => self.name.clone(),
+            false => format!("{} {}", self.name, self.version),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
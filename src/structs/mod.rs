@@ -4,5 +4,6 @@ pub mod companion;
 pub mod game_players;
 pub mod marne;
 pub mod old_games;
+pub mod playground;
 pub mod results;
 pub mod server_info;
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -27,4 +28,11 @@ pub struct OldGameServer {
 pub struct OldGameServerList {
     #[serde(rename = "serverList")]
     pub server_list: Vec<OldGameServer>,
+    // when the list was written, None for documents of older scrapers
+    #[serde(
+        rename = "timeStamp",
+        default,
+        with = "bson::serde_helpers::chrono_datetime_as_bson_datetime_optional"
+    )]
+    pub time_stamp: Option<DateTime<Utc>>,
 }
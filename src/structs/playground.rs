@@ -0,0 +1,19 @@
+use serde::Serialize;
+
+// a Portal/Playground experience as stored in playground_leaderboard
+#[derive(Serialize, Debug, Clone)]
+pub struct PlaygroundEntry {
+    #[serde(rename = "configName")]
+    pub config_name: String,
+    pub servers: i64,
+    pub players: i64,
+    // players of the detailed run before, None for new experiences
+    #[serde(rename = "previousPlayers")]
+    pub previous_players: Option<i64>,
+    pub trend: Option<i64>,
+    // rfc3339
+    #[serde(rename = "firstSeen")]
+    pub first_seen: String,
+    #[serde(rename = "lastSeen")]
+    pub last_seen: String,
+}
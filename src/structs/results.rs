@@ -1,6 +1,6 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::AddAssign};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UnmanagedPlayers {
@@ -36,6 +36,32 @@ pub struct RegionAmounts {
     pub community_queue_amount: i64,
     #[serde(rename = "communitySpectatorAmount")]
     pub community_spectator_amount: i64,
+    // max slots of the servers that report it, and the soldiers on those servers
+    #[serde(rename = "slotAmount", default)]
+    pub slot_amount: i64,
+    #[serde(rename = "slottedSoldierAmount", default)]
+    pub slotted_soldier_amount: i64,
+}
+
+impl RegionAmounts {
+    pub fn add_slots(&mut self, soldiers: i64, max_players: Option<i64>) {
+        if let Some(max_players) = max_players {
+            self.slot_amount += max_players;
+            self.slotted_soldier_amount += soldiers;
+        }
+    }
+
+    // None when no server reported its capacity
+    pub fn fill_rate(&self) -> Option<f64> {
+        fill_rate(self.slotted_soldier_amount, self.slot_amount)
+    }
+}
+
+pub fn fill_rate(soldiers: i64, slots: i64) -> Option<f64> {
+    match slots > 0 {
+        true => Some(soldiers as f64 / slots as f64),
+        false => None,
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -55,26 +81,151 @@ pub struct Metadata {
     pub platform: String,
 }
 
+// what servers get grouped by, every key gets a server and player count
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum Dimension {
+    Map,
+    Mode,
+    Setting,
+    OwnerPlatform,
+    Playground,
+    Mod,
+    Country,
+    // EA ping site (datacenter) of sources without server ips, not the server's country
+    PingSite,
+}
+
+impl Dimension {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dimension::Map => "map",
+            Dimension::Mode => "mode",
+            Dimension::Setting => "setting",
+            Dimension::OwnerPlatform => "ownerPlatform",
+            Dimension::Playground => "playground",
+            Dimension::Mod => "mod",
+            Dimension::Country => "country",
+            Dimension::PingSite => "pingSite",
+        }
+    }
+
+    // influx "type" tags
+    pub fn servers_type(&self) -> &'static str {
+        match self {
+            Dimension::Map => "maps",
+            Dimension::Mode => "modes",
+            Dimension::Setting => "settings",
+            Dimension::OwnerPlatform => "ownerPlatform",
+            Dimension::Playground => "playground",
+            Dimension::Mod => "mods",
+            Dimension::Country => "countries",
+            Dimension::PingSite => "pingSites",
+        }
+    }
+
+    pub fn players_type(&self) -> &'static str {
+        match self {
+            Dimension::Map => "mapPlayers",
+            Dimension::Mode => "modePlayers",
+            Dimension::Setting => "settingPlayers",
+            Dimension::OwnerPlatform => "ownerPlatformPlayers",
+            Dimension::Playground => "playgroundPlayers",
+            Dimension::Mod => "modPlayers",
+            Dimension::Country => "countryPlayers",
+            Dimension::PingSite => "pingSitePlayers",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tally {
+    pub servers: i64,
+    pub players: i64,
+    // same as in RegionAmounts, only servers that report their capacity
+    #[serde(default)]
+    pub slots: i64,
+    #[serde(rename = "slottedPlayers", default)]
+    pub slotted_players: i64,
+}
+
+impl Tally {
+    pub fn fill_rate(&self) -> Option<f64> {
+        fill_rate(self.slotted_players, self.slots)
+    }
+}
+
+impl AddAssign<&Tally> for Tally {
+    fn add_assign(&mut self, other: &Tally) {
+        self.servers += other.servers;
+        self.players += other.players;
+        self.slots += other.slots;
+        self.slotted_players += other.slotted_players;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Breakdown(HashMap<Dimension, HashMap<String, Tally>>);
+
+impl Breakdown {
+    // breakdown of a single server
+    pub fn server<'a>(
+        keys: impl IntoIterator<Item = (Dimension, &'a str)>,
+        players: i64,
+        max_players: Option<i64>,
+    ) -> Self {
+        let mut breakdown = Breakdown::default();
+        for (dimension, key) in keys {
+            breakdown.add(dimension, key, players, max_players);
+        }
+        breakdown
+    }
+
+    // counts one server with its players
+    pub fn add(&mut self, dimension: Dimension, key: &str, players: i64, max_players: Option<i64>) {
+        self.add_tally(
+            dimension,
+            key,
+            &Tally {
+                servers: 1,
+                players,
+                slots: max_players.unwrap_or_default(),
+                slotted_players: max_players.map_or(0, |_| players),
+            },
+        );
+    }
+
+    pub fn add_tally(&mut self, dimension: Dimension, key: &str, tally: &Tally) {
+        *self
+            .0
+            .entry(dimension)
+            .or_default()
+            .entry(key.to_string())
+            .or_default() += tally;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Dimension, &String, &Tally)> {
+        self.0.iter().flat_map(|(dimension, tallies)| {
+            tallies
+                .iter()
+                .map(move |(key, tally)| (*dimension, key, tally))
+        })
+    }
+}
+
+impl AddAssign<&Breakdown> for Breakdown {
+    fn add_assign(&mut self, other: &Breakdown) {
+        for (dimension, key, tally) in other.iter() {
+            self.add_tally(dimension, key, tally);
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RegionResult {
     pub metadata: Metadata,
     pub amounts: RegionAmounts,
-    pub maps: HashMap<String, i64>,
-    #[serde(rename = "mapPlayers")]
-    pub map_players: HashMap<String, i64>,
-    pub modes: HashMap<String, i64>,
-    #[serde(rename = "modePlayers")]
-    pub mode_players: HashMap<String, i64>,
-    pub settings: HashMap<String, i64>,
-    #[serde(rename = "settingPlayers")]
-    pub settings_players: HashMap<String, i64>,
-    #[serde(rename = "ownerPlatform")]
-    pub owner_platform: HashMap<String, i64>,
-    #[serde(rename = "playground")]
-    pub playground: HashMap<String, i64>,
-    #[serde(rename = "playgroundPlayers")]
-    pub playground_players: HashMap<String, i64>,
-    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub breakdown: Breakdown,
     pub timestamp: DateTime<Utc>,
 }
 
@@ -95,71 +246,10 @@ fn combine_regions(first_region: &RegionResult, second_region: &RegionResult) ->
     combined_regions.amounts.community_queue_amount += second_region.amounts.community_queue_amount;
     combined_regions.amounts.community_spectator_amount +=
         second_region.amounts.community_spectator_amount;
+    combined_regions.amounts.slot_amount += second_region.amounts.slot_amount;
+    combined_regions.amounts.slotted_soldier_amount += second_region.amounts.slotted_soldier_amount;
 
-    for (key, value) in &second_region.maps {
-        combined_regions
-            .maps
-            .entry(key.to_string())
-            .and_modify(|count| *count += value)
-            .or_insert(*value);
-    }
-    for (key, value) in &second_region.map_players {
-        combined_regions
-            .map_players
-            .entry(key.to_string())
-            .and_modify(|count| *count += value)
-            .or_insert(*value);
-    }
-    for (key, value) in &second_region.modes {
-        combined_regions
-            .modes
-            .entry(key.to_string())
-            .and_modify(|count| *count += value)
-            .or_insert(*value);
-    }
-    for (key, value) in &second_region.mode_players {
-        combined_regions
-            .mode_players
-            .entry(key.to_string())
-            .and_modify(|count| *count += value)
-            .or_insert(*value);
-    }
-    for (key, value) in &second_region.settings {
-        combined_regions
-            .settings
-            .entry(key.to_string())
-            .and_modify(|count| *count += value)
-            .or_insert(*value);
-    }
-    for (key, value) in &second_region.settings_players {
-        combined_regions
-            .settings_players
-            .entry(key.to_string())
-            .and_modify(|count| *count += value)
-            .or_insert(*value);
-    }
-    for (key, value) in &second_region.owner_platform {
-        combined_regions
-            .owner_platform
-            .entry(key.to_string())
-            .and_modify(|count| *count += value)
-            .or_insert(*value);
-    }
-
-    for (key, value) in &second_region.playground {
-        combined_regions
-            .playground
-            .entry(key.to_string())
-            .and_modify(|count| *count += value)
-            .or_insert(*value);
-    }
-    for (key, value) in &second_region.playground_players {
-        combined_regions
-            .playground_players
-            .entry(key.to_string())
-            .and_modify(|count| *count += value)
-            .or_insert(*value);
-    }
+    combined_regions.breakdown += &second_region.breakdown;
     combined_regions
 }
 
@@ -187,17 +277,11 @@ pub async fn combine_region_players(
             community_soldier_amount: 0,
             community_queue_amount: 0,
             community_spectator_amount: 0,
+            slot_amount: 0,
+            slotted_soldier_amount: 0,
         },
-        maps: HashMap::new(),
-        modes: HashMap::new(),
-        settings: HashMap::new(),
-        playground: HashMap::new(),
-        owner_platform: HashMap::new(),
+        breakdown: Breakdown::default(),
         timestamp: Utc::now(),
-        map_players: HashMap::new(),
-        mode_players: HashMap::new(),
-        settings_players: HashMap::new(),
-        playground_players: HashMap::new(),
     };
 
     for region in region_results.values() {
@@ -220,16 +304,8 @@ pub async fn combine_region_platforms(
                 .and_modify(|all_regions| {
                     let result = combine_regions(all_regions, region_result);
                     all_regions.amounts = result.amounts;
-                    all_regions.maps = result.maps;
-                    all_regions.modes = result.modes;
-                    all_regions.mode_players = result.mode_players;
-                    all_regions.map_players = result.map_players;
-                    all_regions.settings_players = result.settings_players;
                     all_regions.metadata = result.metadata;
-                    all_regions.settings = result.settings;
-                    all_regions.owner_platform = result.owner_platform;
-                    all_regions.playground = result.playground;
-                    all_regions.playground_players = result.playground_players;
+                    all_regions.breakdown = result.breakdown;
                 })
                 .or_insert_with(|| region_result.to_owned());
         }
@@ -237,3 +313,98 @@ pub async fn combine_region_platforms(
 
     all_platforms
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tally(breakdown: &Breakdown, dimension: Dimension, key: &str) -> Tally {
+        breakdown.0[&dimension][key]
+    }
+
+    #[test]
+    fn counts_servers_and_known_slots() {
+        let mut breakdown = Breakdown::server(
+            [(Dimension::Map, "wake"), (Dimension::Mode, "conquest")],
+            30,
+            Some(64),
+        );
+        breakdown.add(Dimension::Map, "wake", 10, None);
+        assert_eq!(
+            tally(&breakdown, Dimension::Map, "wake"),
+            Tally {
+                servers: 2,
+                players: 40,
+                slots: 64,
+                slotted_players: 30,
+            }
+        );
+        assert_eq!(tally(&breakdown, Dimension::Mode, "conquest").servers, 1);
+        assert_eq!(
+            tally(&breakdown, Dimension::Map, "wake").fill_rate(),
+            Some(30.0 / 64.0)
+        );
+        assert_eq!(Tally::default().fill_rate(), None);
+    }
+
+    #[test]
+    fn merges_by_dimension_and_key() {
+        let mut first = Breakdown::server([(Dimension::Map, "wake")], 10, Some(32));
+        let second = Breakdown::server(
+            [(Dimension::Map, "wake"), (Dimension::Map, "kubra")],
+            5,
+            Some(16),
+        );
+        // same key in another dimension stays apart
+        let third = Breakdown::server([(Dimension::Mod, "wake")], 1, None);
+        first += &second;
+        first += &third;
+
+        assert_eq!(
+            tally(&first, Dimension::Map, "wake"),
+            Tally {
+                servers: 2,
+                players: 15,
+                slots: 48,
+                slotted_players: 15,
+            }
+        );
+        assert_eq!(tally(&first, Dimension::Map, "kubra").players, 5);
+        assert_eq!(tally(&first, Dimension::Mod, "wake").players, 1);
+        assert_eq!(first.iter().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn combined_regions_merge_breakdowns() {
+        let mut region = combine_region_players("EU", "pc", &HashMap::new()).await;
+        region.amounts.server_amount = 1;
+        region.breakdown = Breakdown::server([(Dimension::Country, "NL")], 20, Some(64));
+        let regions = HashMap::from([
+            ("EU".to_string(), region.clone()),
+            ("NAm".to_string(), region),
+        ]);
+        let all_regions = combine_region_players("ALL", "pc", &regions).await;
+        assert_eq!(all_regions.amounts.server_amount, 2);
+        assert_eq!(
+            tally(&all_regions.breakdown, Dimension::Country, "NL"),
+            Tally {
+                servers: 2,
+                players: 40,
+                slots: 128,
+                slotted_players: 40,
+            }
+        );
+    }
+
+    #[test]
+    fn survives_a_json_round_trip() {
+        let breakdown = Breakdown::server([(Dimension::PingSite, "aws-fra")], 3, Some(64));
+        let json = serde_json::to_string(&breakdown).unwrap();
+        assert!(json.contains("\"pingSite\""));
+        let parsed: Breakdown = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            tally(&parsed, Dimension::PingSite, "aws-fra"),
+            tally(&breakdown, Dimension::PingSite, "aws-fra")
+        );
+    }
+}
@@ -1,6 +1,8 @@
+use serde::{Deserialize, Serialize};
+
 use super::battlelog;
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServerInfo {
     pub name: String,
     pub guid: String,
@@ -10,6 +12,19 @@ pub struct ServerInfo {
     pub mode: String,
     pub map: String,
     pub is_official: Option<bool>,
+    #[serde(default)]
+    pub max_players: Option<i64>,
+    // "name:value" pairs of the server settings the source exposes
+    #[serde(default)]
+    pub settings: Vec<String>,
+}
+
+// sources report 0 when they don't know the capacity
+pub fn capacity(max_players: i64) -> Option<i64> {
+    match max_players > 0 {
+        true => Some(max_players),
+        false => None,
+    }
 }
 
 impl From<battlelog::BattlelogServer> for ServerInfo {
@@ -23,6 +38,8 @@ impl From<battlelog::BattlelogServer> for ServerInfo {
             mode: "".to_owned(),
             map: server.map,
             is_official: None,
+            max_players: capacity(server.max_soldiers),
+            settings: vec![],
         }
     }
 }